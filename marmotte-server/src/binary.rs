@@ -1,4 +1,4 @@
-use bytes::{BytesMut, BufMut, Bytes};
+use bytes::{BytesMut, BufMut};
 
 pub struct BinaryWriter {
     pub buffer: BytesMut
@@ -13,59 +13,70 @@ impl BinaryWriter {
     pub fn write_string(&mut self, value:&str) {
         let bytes = value.as_bytes();
         let len = bytes.len().to_be_bytes();
-        (*self).buffer.put_slice(&len);
-        (*self).buffer.put_slice(&bytes);
+        self.buffer.put_slice(&len);
+        self.buffer.put_slice(bytes);
     }
 
     pub fn write_i32(&mut self, value:i32) {
         let bytes = value.to_be_bytes();
-        (*self).buffer.put_slice(&bytes);
+        self.buffer.put_slice(&bytes);
     }
 
     pub fn write_u32(&mut self, value:u32) {
         let bytes = value.to_be_bytes();
-        (*self).buffer.put_slice(&bytes);
+        self.buffer.put_slice(&bytes);
     }
 
     pub fn write_i64(&mut self, value:i64) {
         let bytes = value.to_be_bytes();
-        (*self).buffer.put_slice(&bytes);
+        self.buffer.put_slice(&bytes);
     }
 
     pub fn write_u64(&mut self, value:u64) {
         let bytes = value.to_be_bytes();
-        (*self).buffer.put_slice(&bytes);
+        self.buffer.put_slice(&bytes);
     }
 
+    /// Writes the IEEE-754 bits of the value, so NaN payloads, infinities and -0.0 are kept as is.
     pub fn write_f64(&mut self, value:f64) {
-        let v = value as i64;
-        let bytes = v.to_be_bytes();
-        (*self).buffer.put_slice(&bytes);
+        let bytes = value.to_bits().to_be_bytes();
+        self.buffer.put_slice(&bytes);
+    }
+
+    /// Writes an unsigned LEB128 varint: 7 bits per byte, low bits first, the high bit set on every byte but the last.
+    pub fn write_varint(&mut self, value:u64) {
+        let mut value = value;
+        while value >= 0x80 {
+            self.buffer.put_u8((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buffer.put_u8(value as u8);
     }
 
     pub fn write_bool(&mut self, value:bool) {
         let byte:u8 = if value { 1 } else { 0 };
-        (*self).buffer.put_u8(byte);
+        self.buffer.put_u8(byte);
     }
 
     pub fn write_u8(&mut self, byte:u8) {
-        (*self).buffer.put_u8(byte);
+        self.buffer.put_u8(byte);
     }
 
     pub fn write_bytes(&mut self, bytes: &[u8]) {
-        (*self).buffer.put_slice(bytes);
+        self.buffer.put_slice(bytes);
     }
 }
 
-pub struct BinaryReader {
-    pub buffer: Bytes,
+/// Reads the values written by a `BinaryWriter` from a borrowed buffer, without copying it.
+pub struct BinaryReader<'a> {
+    pub buffer: &'a [u8],
     pub position: usize
 }
 
-impl BinaryReader {
+impl<'a> BinaryReader<'a> {
 
-    pub fn from(buffer: BytesMut) -> BinaryReader {
-        BinaryReader { buffer: buffer.freeze(), position: 0 }
+    pub fn from(buffer: &'a [u8]) -> BinaryReader<'a> {
+        BinaryReader { buffer, position: 0 }
     }
 
     pub fn read_string(&mut self) -> Result<String, &str> {
@@ -74,7 +85,7 @@ impl BinaryReader {
         }
         else {
             let mut bl: [u8; 8] = Default::default();
-            bl.copy_from_slice(&self.buffer.slice(self.position .. self.position+8));
+            bl.copy_from_slice(&self.buffer[self.position .. self.position+8]);
             let len = usize::from_be_bytes(bl);
             let pos_start = self.position+8;
             let pos_end = self.position+len+8;
@@ -82,22 +93,52 @@ impl BinaryReader {
             if pos_end > self.buffer.len() {
                 //let msg = (format!("Corrupted data, trying to read string of from {} to {} but length is {}.", pos_start, pos_end, self.buffer.len())).as_str();
                 println!("read_string pos_end > self.buffer.len() => {} > {}", pos_end, self.buffer.len());
-                Err(&"Corrupted data")
+                Err("Corrupted data")
             }
             else {
-                let content = self.buffer.slice(pos_start .. pos_end).to_vec();
+                let content = self.buffer[pos_start .. pos_end].to_vec();
 
                 self.position += 8 + len;
 
                 match String::from_utf8(content) {
                     Ok(s) => Ok(s),
-                    Err(_) => Err(&"Failed to decode UTF8 string.")
+                    Err(_) => Err("Failed to decode UTF8 string.")
                 }
             }
 
         }
     }
 
+    /// Reads a string written by `write_string` as raw bytes, without copying nor decoding them.
+    pub fn read_string_bytes(&mut self) -> Result<&'a [u8], &str> {
+        if self.buffer.len() < self.position + 8 {
+            return Err("Failed to read value due to buffer overflow.");
+        }
+        let mut bl: [u8; 8] = Default::default();
+        bl.copy_from_slice(&self.buffer[self.position .. self.position+8]);
+        let len = usize::from_be_bytes(bl);
+
+        match (self.position + 8).checked_add(len) {
+            Some(pos_end) if pos_end <= self.buffer.len() => {
+                let content = &self.buffer[self.position+8 .. pos_end];
+                self.position = pos_end;
+                Ok(content)
+            },
+            _ => Err("Corrupted data")
+        }
+    }
+
+    /// Moves past `len` bytes.
+    pub fn skip(&mut self, len: usize) -> Result<(), &str> {
+        if self.position.checked_add(len).is_none_or(|end| self.buffer.len() < end) {
+            Err("Failed to skip value due to buffer overflow.")
+        }
+        else {
+            self.position += len;
+            Ok(())
+        }
+    }
+
     pub fn read_i32(&mut self) -> Result<i32, &str> {
         let mut bl: [u8; 4] = Default::default();
 
@@ -105,7 +146,7 @@ impl BinaryReader {
             Err("Failed to read value due to buffer overflow.")
         }
         else {
-            bl.copy_from_slice(&self.buffer.slice(self.position .. self.position+4));
+            bl.copy_from_slice(&self.buffer[self.position .. self.position+4]);
             self.position += 4;
             Ok(i32::from_be_bytes(bl))
         }
@@ -119,7 +160,7 @@ impl BinaryReader {
         }
         else {
             let len = std::mem::size_of::<i64>();
-            bl.copy_from_slice(&self.buffer.slice(self.position .. self.position+len));
+            bl.copy_from_slice(&self.buffer[self.position .. self.position+len]);
             self.position += len;
             Ok(i64::from_be_bytes(bl))
         }
@@ -133,7 +174,7 @@ impl BinaryReader {
         }
         else {
             let len = std::mem::size_of::<u64>();
-            bl.copy_from_slice(&self.buffer.slice(self.position .. self.position+len));
+            bl.copy_from_slice(&self.buffer[self.position .. self.position+len]);
             self.position += len;
             Ok(u64::from_be_bytes(bl))
         }
@@ -142,13 +183,13 @@ impl BinaryReader {
     pub fn read_f64(&mut self) -> Result<f64, &str> {
         let mut bl: [u8; 8] = Default::default();
 
-        if self.buffer.len() <= self.position {
+        if self.buffer.len() < self.position + 8 {
             Err("Failed to read value due to buffer overflow.")
         }
         else {
-            bl.copy_from_slice(&self.buffer.slice(self.position .. self.position+4));
+            bl.copy_from_slice(&self.buffer[self.position .. self.position+8]);
             self.position += 8;
-            Ok(i64::from_be_bytes(bl) as f64)
+            Ok(f64::from_bits(u64::from_be_bytes(bl)))
         }
     }
 
@@ -158,12 +199,29 @@ impl BinaryReader {
             Err("Failed to read value due to buffer overflow.")
         }
         else {
-            bl.copy_from_slice(&self.buffer.slice(self.position .. self.position+4));
+            bl.copy_from_slice(&self.buffer[self.position .. self.position+4]);
             self.position += 4;
             Ok(u32::from_be_bytes(bl))
         }
     }
 
+    pub fn read_varint(&mut self) -> Result<u64, &str> {
+        let mut value: u64 = 0;
+        // a u64 takes at most 10 bytes
+        for i in 0..10 {
+            if self.buffer.len() <= self.position {
+                return Err("Failed to read value due to buffer overflow.");
+            }
+            let byte = self.buffer[self.position];
+            self.position += 1;
+            value |= ((byte & 0x7F) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("Failed to read varint due to corrupted data.")
+    }
+
     pub fn read_u8(&mut self) -> Result<u8, &str> {
         if self.buffer.len() <= self.position {
             Err("Failed to read bool value.")
@@ -210,7 +268,7 @@ mod tests {
         let value = String::from("lorem ipsum");
         wr.write_string(&value);
 
-        let mut reader = BinaryReader::from(wr.buffer);
+        let mut reader = BinaryReader::from(&wr.buffer);
         let result = reader.read_string();
 
         assert_eq!(Ok(value), result);
@@ -224,7 +282,7 @@ mod tests {
         let value:i32 = 983424534;
         wr.write_i32(value);
 
-        let mut reader = BinaryReader::from(wr.buffer);
+        let mut reader = BinaryReader::from(&wr.buffer);
         let result = reader.read_i32();
 
         assert_eq!(Ok(value), result);
@@ -238,7 +296,7 @@ mod tests {
         let value:u32 = 983424534;
         wr.write_u32(value);
 
-        let mut reader = BinaryReader::from(wr.buffer);
+        let mut reader = BinaryReader::from(&wr.buffer);
         let result = reader.read_u32();
 
         assert_eq!(Ok(value), result);
@@ -246,13 +304,48 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn float64_should_be_written_and_read_exactly() -> Result<(), String> {
+        let values = [19.99, -0.0, 48.858_370_1, f64::MIN_POSITIVE, f64::INFINITY, f64::NEG_INFINITY, f64::from_bits(0x7ff8_0000_dead_beef)];
+        let mut wr = BinaryWriter::with_capacity(200);
+        for value in values {
+            wr.write_f64(value);
+        }
+
+        let mut reader = BinaryReader::from(&wr.buffer);
+        for value in values {
+            assert_eq!(Ok(value.to_bits()), reader.read_f64().map(f64::to_bits));
+        }
+        assert!(reader.read_f64().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn varint_should_be_written_and_read() -> Result<(), String> {
+        let values = [0, 1, 127, 128, 300, 16_383, 16_384, u32::MAX as u64, u64::MAX];
+        let mut wr = BinaryWriter::with_capacity(200);
+        for value in values {
+            wr.write_varint(value);
+        }
+        assert_eq!(1 + 1 + 1 + 2 + 2 + 2 + 3 + 5 + 10, wr.buffer.len());
+
+        let mut reader = BinaryReader::from(&wr.buffer);
+        for value in values {
+            assert_eq!(Ok(value), reader.read_varint());
+        }
+        assert!(reader.read_varint().is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn bool_true_should_be_written_and_read() -> Result<(), String> {
         let mut wr = BinaryWriter::with_capacity(200);
         let value = true;
         wr.write_bool(value);
 
-        let mut reader = BinaryReader::from(wr.buffer);
+        let mut reader = BinaryReader::from(&wr.buffer);
         let result = reader.read_bool();
 
         assert_eq!(Ok(value), result);
@@ -266,7 +359,7 @@ mod tests {
         let value = false;
         wr.write_bool(value);
 
-        let mut reader = BinaryReader::from(wr.buffer);
+        let mut reader = BinaryReader::from(&wr.buffer);
         let result = reader.read_bool();
 
         assert_eq!(Ok(value), result);
@@ -287,7 +380,7 @@ mod tests {
         wr.write_bool(b);
         wr.write_string(&s2);
 
-        let mut reader = BinaryReader::from(wr.buffer);
+        let mut reader = BinaryReader::from(&wr.buffer);
 
         assert_eq!(Ok(s1), reader.read_string());
         assert_eq!(Ok(i), reader.read_i32());
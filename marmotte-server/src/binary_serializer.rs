@@ -1,7 +1,13 @@
+use std::collections::{BTreeMap, VecDeque};
 use bytes::{BytesMut, Bytes};
-use serde_json::Map;
+use serde_json::{Map, Number};
+use serde_json::value::RawValue;
 
 use crate::binary::*;
+use crate::key_dictionary::KeyDictionary;
+use crate::storage::compaction::CompactionReport;
+use crate::storage::disk_writer::DiskWriter;
+use crate::storage::error::StorageError;
 
 use serde_json::Value;
 
@@ -20,32 +26,117 @@ use serde_json::Value;
 | TypeFlag (1 byte) | Data (bytes) |
 ```
 
+#### Null:
+
+```text
+| TypeFlag | Data   |
+| 0        | none   |
+```
+
 #### Bool:
 
 ```text
 | TypeFlag | Data   |
-| 0        | 1 byte |
+| 1        | 1 byte |
+```
+
+#### Int64:
+
+```text
+| TypeFlag | Data     |
+| 2        | 8 bytes  |
 ```
 
-#### Number:
+#### Float:
+
+```text
+| TypeFlag | Data                           |
+| 7        | 8 bytes, IEEE-754 bits of f64  |
+```
+
+Documents written before Float existed store floats under TypeFlag 3 (TruncatedFloat) as the i64 the value was truncated to.
+They are still read, as that integer: the fractional part was never written and cannot be recovered.
+`BinarySerializer::upgrade_document` rewrites such a document without TruncatedFloat values,
+and `BinarySerializer::upgrade_documents` every document of a file.
+
+#### UInt64:
 
 ```text
 | TypeFlag | Data     |
-| 1        | 64 bytes |
+| 8        | 8 bytes  |
 ```
 
+#### Decimal:
+
+```text
+| TypeFlag | Length prefix | Data                        |
+| 9        | 8 bytes       | number as written in JSON   |
+```
+
+#### IndexedObject:
+
+```text
+| TypeFlag | Property count | Size    | Offset table              | Properties       |
+| 10       | 8 bytes        | 8 bytes | count * (4 + 8) bytes     | as in an Object  |
+```
+
+Size is the length of the offset table and properties, so the whole object is skipped at once.
+Each entry of the offset table is the crc32 of a property name and the offset of that property from the start of the properties,
+entries are sorted by hash so a reader finds a property with a binary search. Objects use this layout when
+`BinarySerializer::indexed_objects` is set, documents written with plain Objects are still read.
+
+#### KeyedObject:
+
+```text
+| TypeFlag | Property count | Size    | Properties                  |
+| 11       | varint         | 8 bytes | key id (varint) and value   |
+```
+
+Size is the length of the properties, like in a SizedObject. Property names are replaced by their id in the `KeyDictionary` of the collection, which is needed to read the document.
+Objects use this layout when `BinarySerializer::keys` is set, which cannot be combined with `indexed_objects`.
+
+#### SizedArray:
+
+```text
+| TypeFlag | Item count | Size    | Items                 |
+| 12       | 8 bytes    | 8 bytes | TypeFlag and Data     |
+```
+
+#### SizedObject:
+
+```text
+| TypeFlag | Property count | Size    | Properties             |
+| 13       | 8 bytes        | 8 bytes | Name (Text) and value  |
+```
+
+Size is the length of the items or properties, so a value is skipped at once while looking a property up, whatever it holds.
+Arrays and objects are written in these layouts, unless objects are written as IndexedObject or KeyedObject.
+Documents written before them hold Arrays and Objects, without size, which are still read.
+
+A number is written with the first of Int64, UInt64 and Float giving back the same JSON text,
+and as a Decimal when none does: too large for 64 bits, more digits than a f64 holds, or written like `1e3` or `-0`.
+The text of a number is only known when the document is serialized from its JSON text, see `JsonDocument`:
+a `Value` holds its numbers as i64, u64 or f64, and reads a Decimal back as the nearest of them.
+
 #### Text:
 
 ```text
 | TypeFlag | Length prefix | Data     |
-| 2        | 64 bytes      | bytes    |
+| 4        | 8 bytes       | bytes    |
 ```
 
 #### Array:
 
 ```text
-| TypeFlag | Length prefix | Data     |
-| 3        | 64 bytes      | bytes    |
+| TypeFlag | Item count | Items                 |
+| 5        | 8 bytes    | TypeFlag and Data     |
+```
+
+#### Object:
+
+```text
+| TypeFlag | Property count | Properties             |
+| 6        | 8 bytes        | Name (Text) and value  |
 ```
 
 */
@@ -55,10 +146,25 @@ pub enum TypeFlag {
     Null,
     Bool,
     Int64,
-    Float,
+    /// Written before `Float`, the value truncated to an i64. Read only.
+    TruncatedFloat,
     Text,
+    /// Written before `SizedArray`. Read only.
     Array,
-    Object
+    /// Written before `SizedObject`. Read only.
+    Object,
+    Float,
+    UInt64,
+    /// A number as its JSON text, for those no other number type holds exactly.
+    Decimal,
+    /// An object with an offset table, to jump to a property without reading the previous ones.
+    IndexedObject,
+    /// An object with property names replaced by their id in a `KeyDictionary`.
+    KeyedObject,
+    /// An array with the size of its items, to skip it without reading them.
+    SizedArray,
+    /// An object with the size of its properties, to skip it without reading them.
+    SizedObject
 }
 
 impl TypeFlag {
@@ -68,47 +174,122 @@ impl TypeFlag {
             TypeFlag::Null => 0,
             TypeFlag::Bool => 1,
             TypeFlag::Int64 => 2,
-            TypeFlag::Float => 3,
+            TypeFlag::TruncatedFloat => 3,
             TypeFlag::Text => 4,
             TypeFlag::Array => 5,
-            TypeFlag::Object => 6
+            TypeFlag::Object => 6,
+            TypeFlag::Float => 7,
+            TypeFlag::UInt64 => 8,
+            TypeFlag::Decimal => 9,
+            TypeFlag::IndexedObject => 10,
+            TypeFlag::KeyedObject => 11,
+            TypeFlag::SizedArray => 12,
+            TypeFlag::SizedObject => 13
         }
     }
 
-    fn From(v: u8) -> Result<TypeFlag, String>{
+    fn from(v: u8) -> Result<TypeFlag, String>{
         match v {
             0 => Ok(TypeFlag::Null),
             1 => Ok(TypeFlag::Bool),
             2 => Ok(TypeFlag::Int64),
-            3 => Ok(TypeFlag::Float),
+            3 => Ok(TypeFlag::TruncatedFloat),
             4 => Ok(TypeFlag::Text),
             5 => Ok(TypeFlag::Array),
             6 => Ok(TypeFlag::Object),
+            7 => Ok(TypeFlag::Float),
+            8 => Ok(TypeFlag::UInt64),
+            9 => Ok(TypeFlag::Decimal),
+            10 => Ok(TypeFlag::IndexedObject),
+            11 => Ok(TypeFlag::KeyedObject),
+            12 => Ok(TypeFlag::SizedArray),
+            13 => Ok(TypeFlag::SizedObject),
             n => Err(format!("{} is not a valid type flag.", n))
         }
     }
 
 }
 
-pub struct BinarySerializer {
-    pub writer : Box<BinaryWriter>
+/// Size of an entry of the offset table of an IndexedObject: hash of the property name and offset of the property.
+const PROPERTY_ENTRY_SIZE: usize = 4 + 8;
+
+/// JSON document parsed into a `Value`, with the text of its numbers, which a `Value` does not keep.
+pub struct JsonDocument {
+    pub value: Value,
+    /// Texts of the numbers of `value`, in the order they are serialized: properties in the order of `Map`, items in order.
+    number_texts: VecDeque<String>
+}
+
+impl JsonDocument {
+
+    pub fn parse(json: &str) -> Result<JsonDocument, serde_json::Error> {
+        let value = serde_json::from_str::<Value>(json)?;
+        let mut number_texts = VecDeque::new();
+        JsonDocument::collect_number_texts(serde_json::from_str::<&RawValue>(json)?, &mut number_texts)?;
+        Ok(JsonDocument { value, number_texts })
+    }
+
+    fn collect_number_texts(raw: &RawValue, number_texts: &mut VecDeque<String>) -> Result<(), serde_json::Error> {
+        match raw.get().as_bytes().first() {
+            Some(b'{') => {
+                // sorted and deduplicated like the properties of a `Map`
+                for value in serde_json::from_str::<BTreeMap<String, &RawValue>>(raw.get())?.into_values() {
+                    JsonDocument::collect_number_texts(value, number_texts)?;
+                }
+            },
+            Some(b'[') => {
+                for item in serde_json::from_str::<Vec<&RawValue>>(raw.get())? {
+                    JsonDocument::collect_number_texts(item, number_texts)?;
+                }
+            },
+            Some(b'-' | b'0'..=b'9') => number_texts.push_back(String::from(raw.get())),
+            _ => {}
+        }
+        Ok(())
+    }
+
+}
+
+pub struct BinarySerializer<'k> {
+    pub writer : Box<BinaryWriter>,
+    /// Objects are written as IndexedObject, with an offset table, instead of SizedObject.
+    pub indexed_objects: bool,
+    /// Objects are written as KeyedObject, with the ids of their property names in this dictionary, instead of SizedObject.
+    /// Every property name must be in it already, see `KeyDictionary::serialize_json`.
+    /// Serializing an object fails if `indexed_objects` is set too.
+    pub keys: Option<&'k KeyDictionary>
+}
+
+impl<'k> Default for BinarySerializer<'k> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl BinarySerializer {
+impl<'k> BinarySerializer<'k> {
 
-    pub fn new() -> BinarySerializer {
+    pub fn new() -> BinarySerializer<'k> {
         let wr = BinaryWriter { buffer: BytesMut::new() };
-        BinarySerializer { writer:Box::new(wr) }
+        BinarySerializer { writer:Box::new(wr), indexed_objects: false, keys: None }
     }
 
-    pub fn serialize_json<'s>(json:&String) -> Result<Bytes, &'s str> {
+    pub fn serialize_json<'s>(json: &str) -> Result<Bytes, &'s str> {
+        BinarySerializer::serialize_json_with_layout(json, false)
+    }
+
+    /// Serializes the document with every object written as an IndexedObject.
+    pub fn serialize_json_indexed<'s>(json: &str) -> Result<Bytes, &'s str> {
+        BinarySerializer::serialize_json_with_layout(json, true)
+    }
 
-        match serde_json::from_str::<Value>(json) {
-            Err(_) => Err(&"Could not parse JSON"),
-            Ok(value) => {
+    fn serialize_json_with_layout<'s>(json: &str, indexed_objects: bool) -> Result<Bytes, &'s str> {
+
+        match JsonDocument::parse(json) {
+            Err(_) => Err("Could not parse JSON"),
+            Ok(document) => {
                 let wr = BinaryWriter { buffer: BytesMut::with_capacity(json.len()) };
-                let mut serializer = BinarySerializer { writer:Box::new(wr) };
-                match serializer.serialize_json_value(&value, json.len()) {
+                let mut serializer = BinarySerializer { writer:Box::new(wr), indexed_objects, keys: None };
+                match serializer.serialize_document(document, json.len()) {
                     Ok(_) => {
                         let b = serializer.writer.buffer;
                         let f = b.freeze();
@@ -120,17 +301,33 @@ impl BinarySerializer {
         }
     }
 
+    /// Serializes the value, its numbers being written with the text a `Value` gives them.
     pub fn serialize_json_value<'s>(&mut self, json: &Value, max_capacity: usize) -> Result<(), &'s str> {
+        self.serialize_value(json, max_capacity, &mut VecDeque::new())
+    }
+
+    /// Serializes the document, its numbers being written with their text in the document.
+    pub fn serialize_document<'s>(&mut self, document: JsonDocument, max_capacity: usize) -> Result<(), &'s str> {
+        let mut number_texts = document.number_texts;
+        self.serialize_value(&document.value, max_capacity, &mut number_texts)
+    }
+
+    fn serialize_value<'s>(&mut self, json: &Value, max_capacity: usize, number_texts: &mut VecDeque<String>) -> Result<(), &'s str> {
         //let mut callstack: LinkedList<&Value> = LinkedList::new();
         match json {
+            Value::Object(_) if self.indexed_objects && self.keys.is_some() => Err("indexed objects cannot be written with a key dictionary"),
+            Value::Object(o) if self.indexed_objects => self.serialize_indexed_object(o, max_capacity, number_texts),
+            Value::Object(o) if self.keys.is_some() => self.serialize_keyed_object(o, max_capacity, number_texts),
             Value::Object(o) => {
-                self.writer.write_u8(TypeFlag::Object.to_bin());
+                self.writer.write_u8(TypeFlag::SizedObject.to_bin());
                 let len = o.len() as u64;
                 self.writer.write_bytes(&len.to_be_bytes());
+                let size_position = self.write_size_placeholder();
                 for key in o.keys() {
                     self.writer.write_string(key);
-                    self.serialize_json_value(&o[key], max_capacity).expect("cannot serialize json array");
+                    self.serialize_value(&o[key], max_capacity, number_texts)?;
                 }
+                self.write_size(size_position);
                 Ok(())
             },
             Value::Null => {
@@ -143,21 +340,8 @@ impl BinarySerializer {
                 Ok(())
             },
             Value::Number(number) => {
-                match number.as_i64() {
-                    Some(n) => {
-                        self.writer.write_u8(TypeFlag::Int64.to_bin());
-                        self.writer.write_i64(n);
-                    },
-                    None => {
-                        match number.as_f64() {
-                            None => {},
-                            Some(f) => {
-                                self.writer.write_u8(TypeFlag::Float.to_bin());
-                                self.writer.write_f64(f);
-                            }
-                        }
-                    }
-                }
+                let text = number_texts.pop_front().unwrap_or_else(|| number.to_string());
+                self.serialize_number_text(number, &text);
                 Ok(())
             },
             Value::String(s) => {
@@ -166,32 +350,122 @@ impl BinarySerializer {
                 Ok(())
             },
             Value::Array(a) => {
-                self.writer.write_u8(TypeFlag::Array.to_bin());
+                self.writer.write_u8(TypeFlag::SizedArray.to_bin());
                 let len = a.len().to_be_bytes();
                 self.writer.write_bytes(&len);
+                let size_position = self.write_size_placeholder();
                 for item in a {
                     //callstack.push_back(item);
-                    self.serialize_json_value(&item, max_capacity).expect("cannot serialize json array");
+                    self.serialize_value(item, max_capacity, number_texts)?;
                 }
+                self.write_size(size_position);
                 Ok(())
-            },
-            _ => Err(&"cannot convert this kind of document.")
+            }
+        }
+
+    }
+
+    /// Writes the size of a SizedArray, SizedObject or KeyedObject as 0, returning its position for `write_size`.
+    fn write_size_placeholder(&mut self) -> usize {
+        let size_position = self.writer.buffer.len();
+        self.writer.write_u64(0);
+        size_position
+    }
+
+    /// Writes the size of a SizedArray, SizedObject or KeyedObject, once its content is written.
+    fn write_size(&mut self, size_position: usize) {
+        let size = (self.writer.buffer.len() - size_position - 8) as u64;
+        self.writer.buffer[size_position..size_position + 8].copy_from_slice(&size.to_be_bytes());
+    }
+
+    fn serialize_indexed_object<'s>(&mut self, o: &Map<String, Value>, max_capacity: usize, number_texts: &mut VecDeque<String>) -> Result<(), &'s str> {
+        self.writer.write_u8(TypeFlag::IndexedObject.to_bin());
+        self.writer.write_u64(o.len() as u64);
+        let size_position = self.writer.buffer.len();
+        self.writer.write_u64(0);
+        let table_position = self.writer.buffer.len();
+        self.writer.write_bytes(&vec![0; o.len() * PROPERTY_ENTRY_SIZE]);
+
+        let properties_position = self.writer.buffer.len();
+        let mut entries: Vec<(u32, u64)> = Vec::with_capacity(o.len());
+        for (key, value) in o {
+            entries.push((crc32fast::hash(key.as_bytes()), (self.writer.buffer.len() - properties_position) as u64));
+            self.writer.write_string(key);
+            self.serialize_value(value, max_capacity, number_texts)?;
         }
+        entries.sort();
 
+        // the table and size are known once the properties are written
+        let size = (self.writer.buffer.len() - table_position) as u64;
+        self.writer.buffer[size_position..table_position].copy_from_slice(&size.to_be_bytes());
+        for (i, (hash, offset)) in entries.into_iter().enumerate() {
+            let entry_position = table_position + i * PROPERTY_ENTRY_SIZE;
+            self.writer.buffer[entry_position..entry_position + 4].copy_from_slice(&hash.to_be_bytes());
+            self.writer.buffer[entry_position + 4..entry_position + PROPERTY_ENTRY_SIZE].copy_from_slice(&offset.to_be_bytes());
+        }
+        Ok(())
+    }
+
+    fn serialize_keyed_object<'s>(&mut self, o: &Map<String, Value>, max_capacity: usize, number_texts: &mut VecDeque<String>) -> Result<(), &'s str> {
+        let keys = self.keys.ok_or("no key dictionary to write a KeyedObject")?;
+        self.writer.write_u8(TypeFlag::KeyedObject.to_bin());
+        self.writer.write_varint(o.len() as u64);
+        let size_position = self.write_size_placeholder();
+        for (key, value) in o {
+            let id = keys.id_of(key).ok_or("property name missing from the key dictionary")?;
+            self.writer.write_varint(id);
+            self.serialize_value(value, max_capacity, number_texts)?;
+        }
+        self.write_size(size_position);
+        Ok(())
+    }
+
+    /// Writes the number with the first type reading back as the same JSON text, see the datagram description.
+    pub fn serialize_number(&mut self, number: &Number) {
+        self.serialize_number_text(number, &number.to_string());
     }
 
-    pub fn read_json_object_properties(reader: &mut BinaryReader) -> Result<Value, String> {
+    /// Writes the number like `serialize_number`, `text` being how the document wrote it.
+    fn serialize_number_text(&mut self, number: &Number, text: &str) {
+        if let Some(n) = number.as_i64().filter(|n| n.to_string() == text) {
+            self.writer.write_u8(TypeFlag::Int64.to_bin());
+            self.writer.write_i64(n);
+        } else if let Some(n) = number.as_u64().filter(|n| n.to_string() == text) {
+            self.writer.write_u8(TypeFlag::UInt64.to_bin());
+            self.writer.write_u64(n);
+        } else if let Some(f) = number.as_f64().filter(|f| Number::from_f64(*f).is_some_and(|n| n.to_string() == text)) {
+            self.serialize_f64(f);
+        } else {
+            self.writer.write_u8(TypeFlag::Decimal.to_bin());
+            self.writer.write_string(text);
+        }
+    }
+
+    /// Writes a Float value. Any f64 is written exactly, NaN and infinities included,
+    /// but those have no JSON representation and are read back as `Value::Null` by `read_value`.
+    pub fn serialize_f64(&mut self, value: f64) {
+        self.writer.write_u8(TypeFlag::Float.to_bin());
+        self.writer.write_f64(value);
+    }
+
+    /// Reads a Float value as written by `serialize_f64`, bit for bit.
+    pub fn read_f64_value(reader: &mut BinaryReader) -> Result<f64, String> {
+        let flag_data = reader.read_u8()?;
+        match TypeFlag::from(flag_data)? {
+            TypeFlag::Float => Ok(reader.read_f64()?),
+            _ => Err(format!("{} is not a Float type flag.", flag_data))
+        }
+    }
+
+    fn read_object_properties(reader: &mut BinaryReader, keys: Option<&KeyDictionary>) -> Result<Value, String> {
         let property_count = reader.read_u64()?;
         let mut properties: Map<String, Value> = Map::new();
 
         for _ in 0..property_count {
-            let name = reader.read_string()
-                .or_else(|e| {
-                    Err(format!("deserialize_json: cannot read property name : {}", e))
-                })?;
+            let name = reader.read_string().map_err(|e| format!("deserialize_json: cannot read property name : {}", e))?;
             let flag_data = reader.read_u8()?;
-            let flag = TypeFlag::From(flag_data).or_else(|e| { Err(format!("cannot read property type : {}", e)) })?;
-            let value = BinarySerializer::read_value(flag, reader)?;
+            let flag = TypeFlag::from(flag_data).map_err(|e| format!("cannot read property type : {}", e))?;
+            let value = BinarySerializer::read_value_with_keys(flag, reader, keys)?;
 
             properties.insert(name, value);
         }
@@ -200,79 +474,390 @@ impl BinarySerializer {
     }
 
     pub fn read_json_object(reader: &mut BinaryReader) -> Result<Value, String> {
+        BinarySerializer::read_json_object_with_keys(reader, None)
+    }
+
+    /// Reads a document, resolving the property names of KeyedObjects with `keys`.
+    pub fn read_json_object_with_keys(reader: &mut BinaryReader, keys: Option<&KeyDictionary>) -> Result<Value, String> {
 
         let flag_data = reader.read_u8()?;
-        let flag = TypeFlag::From(flag_data).or_else(|e| { Err(format!("cannot read property type : {}", e)) })?;
+        let flag = TypeFlag::from(flag_data).map_err(|e| format!("cannot read property type : {}", e))?;
 
-        BinarySerializer::read_json_object_properties(reader)
+        match flag {
+            TypeFlag::SizedObject | TypeFlag::IndexedObject | TypeFlag::KeyedObject => BinarySerializer::read_value_with_keys(flag, reader, keys),
+            _ => BinarySerializer::read_object_properties(reader, keys)
+        }
     }
 
     pub fn read_value(t: TypeFlag, reader: &mut BinaryReader) -> Result<Value, String> {
+        BinarySerializer::read_value_with_keys(t, reader, None)
+    }
+
+    /// Reads a value, resolving the property names of KeyedObjects with `keys`.
+    pub fn read_value_with_keys(t: TypeFlag, reader: &mut BinaryReader, keys: Option<&KeyDictionary>) -> Result<Value, String> {
         match t {
-            TypeFlag::Null => {
-                reader.read_bool();
-                Ok(Value::Null)
-            },
+            // written without data
+            TypeFlag::Null => Ok(Value::Null),
             TypeFlag::Bool => Ok(Value::Bool(reader.read_bool().map_err(String::from)?)),
             TypeFlag::Text => Ok(Value::String(reader.read_string().map_err(String::from)?)),
             TypeFlag::Int64 => {
                 let v = reader.read_i64().map_err(String::from)?;
-                Ok(serde_json::to_value(v).or_else(|_| { Err(format!("cannot read Int64 {}", v)) })?)
+                Ok(serde_json::to_value(v).map_err(|_| format!("cannot read Int64 {}", v))?)
             },
             TypeFlag::Float => {
                 let v = reader.read_f64().map_err(String::from)?;
-                Ok(serde_json::to_value(v).or_else(|_| { Err(format!("cannot read Float {}", v)) })?)
+                Ok(serde_json::to_value(v).map_err(|_| format!("cannot read Float {}", v))?)
             },
-            TypeFlag::Array => {
+            TypeFlag::UInt64 => {
+                let v = reader.read_u64().map_err(String::from)?;
+                Ok(Value::Number(Number::from(v)))
+            },
+            TypeFlag::Decimal => {
+                let v = reader.read_string().map_err(String::from)?;
+                v.parse::<Number>().map(Value::Number).map_err(|_| format!("cannot read Decimal {}", v))
+            },
+            TypeFlag::TruncatedFloat => {
+                let v = reader.read_i64().map_err(String::from)?;
+                serde_json::to_value(v).map_err(|_| format!("cannot read TruncatedFloat {}", v))
+            },
+            TypeFlag::Array | TypeFlag::SizedArray => {
                 let count = reader.read_i64().map_err(String::from)?;
+                if t == TypeFlag::SizedArray {
+                    reader.skip(8)?;
+                }
                 let mut items: Vec<Value> = Vec::new();
                 for _ in 0..count {
                     let flag_data = reader.read_u8()?;
-                    let flag = TypeFlag::From(flag_data).or_else(|e| { Err(format!("cannot read property type : {}", e)) })?;
-                    let value = BinarySerializer::read_value(flag, reader)?;
+                    let flag = TypeFlag::from(flag_data).map_err(|e| format!("cannot read property type : {}", e))?;
+                    let value = BinarySerializer::read_value_with_keys(flag, reader, keys)?;
                     items.push(value);
                 }
                 Ok(Value::Array(items))
             },
             TypeFlag::Object => {
-                BinarySerializer::read_json_object_properties(reader)
+                BinarySerializer::read_object_properties(reader, keys)
             },
-            _ => Err(String::from("not implemented."))
+            TypeFlag::SizedObject | TypeFlag::IndexedObject => {
+                // the properties follow the size and the offset table, in the Object layout
+                let property_count = reader.read_u64()?;
+                reader.skip(8)?;
+                if t == TypeFlag::IndexedObject {
+                    reader.skip(BinarySerializer::offset_table_size(property_count)?)?;
+                }
+                let mut properties: Map<String, Value> = Map::new();
+
+                for _ in 0..property_count {
+                    let name = reader.read_string().map_err(|e| format!("deserialize_json: cannot read property name : {}", e))?;
+                    let flag = BinarySerializer::read_flag(reader)?;
+                    properties.insert(name, BinarySerializer::read_value_with_keys(flag, reader, keys)?);
+                }
+                Ok(Value::Object(properties))
+            },
+            TypeFlag::KeyedObject => {
+                let keys = keys.ok_or("cannot read a KeyedObject without its key dictionary")?;
+                let property_count = reader.read_varint()?;
+                reader.skip(8)?;
+                let mut properties: Map<String, Value> = Map::new();
+
+                for _ in 0..property_count {
+                    let id = reader.read_varint()?;
+                    let name = keys.name_of(id).ok_or_else(|| format!("key {} is not in the key dictionary", id))?;
+                    let flag = BinarySerializer::read_flag(reader)?;
+                    properties.insert(String::from(name), BinarySerializer::read_value_with_keys(flag, reader, Some(keys))?);
+                }
+                Ok(Value::Object(properties))
+            }
+        }
+    }
+
+    fn read_flag(reader: &mut BinaryReader) -> Result<TypeFlag, String> {
+        let flag_data = reader.read_u8()?;
+        TypeFlag::from(flag_data).map_err(|e| format!("cannot read property type : {}", e))
+    }
+
+    /// Moves the reader past a value of type `t` without decoding it.
+    pub fn skip_value(t: TypeFlag, reader: &mut BinaryReader) -> Result<(), String> {
+        match t {
+            TypeFlag::Null => Ok(()),
+            TypeFlag::Bool => Ok(reader.skip(1)?),
+            TypeFlag::Int64 | TypeFlag::TruncatedFloat | TypeFlag::Float | TypeFlag::UInt64 => Ok(reader.skip(8)?),
+            TypeFlag::Text | TypeFlag::Decimal => reader.read_string_bytes().map(|_| ()).map_err(String::from),
+            TypeFlag::Array => {
+                let count = reader.read_u64()?;
+                for _ in 0..count {
+                    let flag = BinarySerializer::read_flag(reader)?;
+                    BinarySerializer::skip_value(flag, reader)?;
+                }
+                Ok(())
+            },
+            TypeFlag::Object => {
+                let property_count = reader.read_u64()?;
+                for _ in 0..property_count {
+                    reader.read_string_bytes()?;
+                    let flag = BinarySerializer::read_flag(reader)?;
+                    BinarySerializer::skip_value(flag, reader)?;
+                }
+                Ok(())
+            },
+            TypeFlag::IndexedObject | TypeFlag::SizedArray | TypeFlag::SizedObject => {
+                reader.skip(8)?;
+                let size = reader.read_u64()?;
+                Ok(reader.skip(size as usize)?)
+            },
+            TypeFlag::KeyedObject => {
+                reader.read_varint()?;
+                let size = reader.read_u64()?;
+                Ok(reader.skip(size as usize)?)
+            }
         }
     }
 
+    /// Returns the values at `path` in a serialized document, like `document::get_property_value` on the deserialized one:
+    /// the path is a list of property names separated by dots, arrays met on the way are looked into item by item,
+    /// and null values are left out.
+    ///
+    /// Only the matched values are decoded, the other properties are skipped without being decoded.
+    pub fn get_property_value(src: &[u8], path: &str) -> Result<Vec<Value>, String> {
+        BinarySerializer::get_property_value_with_keys(src, path, None)
+    }
+
+    /// Returns the values at `path` like `get_property_value`, resolving the property names of KeyedObjects with `keys`.
+    pub fn get_property_value_with_keys(src: &[u8], path: &str, keys: Option<&KeyDictionary>) -> Result<Vec<Value>, String> {
+        let mut reader = BinaryReader::from(src);
+        let parts: Vec<&[u8]> = path.split('.').map(str::as_bytes).collect();
+        let mut values = Vec::new();
+
+        let flag = BinarySerializer::read_flag(&mut reader)?;
+        BinarySerializer::match_property_path(flag, &mut reader, &parts, keys, &mut values)?;
+        Ok(values)
+    }
+
+    fn match_property_path(t: TypeFlag, reader: &mut BinaryReader, parts: &[&[u8]], keys: Option<&KeyDictionary>, values: &mut Vec<Value>) -> Result<(), String> {
+        let Some((part, next_parts)) = parts.split_first() else {
+            let value = BinarySerializer::read_value_with_keys(t, reader, keys)?;
+            if value != Value::Null {
+                values.push(value);
+            }
+            return Ok(());
+        };
+
+        match t {
+            TypeFlag::Array | TypeFlag::SizedArray => {
+                let count = reader.read_u64()?;
+                if t == TypeFlag::SizedArray {
+                    reader.skip(8)?;
+                }
+                for _ in 0..count {
+                    let flag = BinarySerializer::read_flag(reader)?;
+                    BinarySerializer::match_property_path(flag, reader, parts, keys, values)?;
+                }
+                Ok(())
+            },
+            TypeFlag::Object | TypeFlag::SizedObject => {
+                let property_count = reader.read_u64()?;
+                if t == TypeFlag::SizedObject {
+                    reader.skip(8)?;
+                }
+                for _ in 0..property_count {
+                    let name = reader.read_string_bytes()?;
+                    let flag = BinarySerializer::read_flag(reader)?;
+                    if name == *part {
+                        BinarySerializer::match_property_path(flag, reader, next_parts, keys, values)?;
+                    } else {
+                        BinarySerializer::skip_value(flag, reader)?;
+                    }
+                }
+                Ok(())
+            },
+            TypeFlag::KeyedObject => {
+                let dictionary = keys.ok_or("cannot read a KeyedObject without its key dictionary")?;
+                // a name missing from the dictionary is in no document
+                let part_id = std::str::from_utf8(part).ok().and_then(|name| dictionary.id_of(name));
+                let property_count = reader.read_varint()?;
+                reader.skip(8)?;
+                for _ in 0..property_count {
+                    let id = reader.read_varint()?;
+                    let flag = BinarySerializer::read_flag(reader)?;
+                    if Some(id) == part_id {
+                        BinarySerializer::match_property_path(flag, reader, next_parts, keys, values)?;
+                    } else {
+                        BinarySerializer::skip_value(flag, reader)?;
+                    }
+                }
+                Ok(())
+            },
+            TypeFlag::IndexedObject => BinarySerializer::match_indexed_property_path(reader, part, next_parts, keys, values),
+            _ => BinarySerializer::skip_value(t, reader)
+        }
+    }
+
+    /// Returns the size of the offset table of an IndexedObject of `property_count` properties, failing on a count read from corrupted data.
+    fn offset_table_size(property_count: u64) -> Result<usize, String> {
+        usize::try_from(property_count).ok()
+            .and_then(|count| count.checked_mul(PROPERTY_ENTRY_SIZE))
+            .ok_or_else(|| String::from("Corrupted data"))
+    }
+
+    /// Looks `part` up in the offset table of an IndexedObject, then leaves the reader at the end of the object.
+    fn match_indexed_property_path(reader: &mut BinaryReader, part: &[u8], next_parts: &[&[u8]], keys: Option<&KeyDictionary>, values: &mut Vec<Value>) -> Result<(), String> {
+        let property_count = reader.read_u64()?;
+        let size = reader.read_u64()?;
+        let table_position = reader.position;
+        let properties_position = table_position.checked_add(BinarySerializer::offset_table_size(property_count)?);
+        let end = usize::try_from(size).ok().and_then(|size| table_position.checked_add(size));
+        let (properties_position, end) = match (properties_position, end) {
+            (Some(properties_position), Some(end)) if properties_position <= end && end <= reader.buffer.len() => (properties_position, end),
+            _ => return Err(String::from("Corrupted data"))
+        };
+        let property_count = property_count as usize;
+
+        let entry = |i: usize| {
+            let entry_position = table_position + i * PROPERTY_ENTRY_SIZE;
+            let hash = u32::from_be_bytes(reader.buffer[entry_position..entry_position + 4].try_into().unwrap());
+            let offset = u64::from_be_bytes(reader.buffer[entry_position + 4..entry_position + PROPERTY_ENTRY_SIZE].try_into().unwrap());
+            (hash, offset as usize)
+        };
+        let hash = crc32fast::hash(part);
+        let first = {
+            let (mut low, mut high) = (0, property_count);
+            while low < high {
+                let middle = (low + high) / 2;
+                if entry(middle).0 < hash { low = middle + 1 } else { high = middle }
+            }
+            low
+        };
+        let offsets: Vec<usize> = (first..property_count).map(entry).take_while(|(h, _)| *h == hash).map(|(_, offset)| offset).collect();
+
+        // names with the same hash are told apart by reading them
+        for offset in offsets {
+            reader.position = match properties_position.checked_add(offset) {
+                Some(position) if position < end => position,
+                _ => return Err(String::from("Corrupted data"))
+            };
+            let name = reader.read_string_bytes()?;
+            if name == part {
+                let flag = BinarySerializer::read_flag(reader)?;
+                BinarySerializer::match_property_path(flag, reader, next_parts, keys, values)?;
+                break;
+            }
+        }
+        reader.position = end;
+        Ok(())
+    }
+
     pub fn deserialize_json(src: &[u8]) -> Result<Value, String> {
-        let bytes = BytesMut::from(src);
-        let mut reader = BinaryReader::from(bytes);
+        let mut reader = BinaryReader::from(src);
         BinarySerializer::read_json_object(&mut reader)
     }
 
+    /// Rewrites a document in the current encoding. TruncatedFloat values become Int64 ones holding the same integer,
+    /// so a document is never written with them again. Every other byte is kept, whatever the layout of its objects.
+    pub fn upgrade_document(src: &[u8]) -> Result<Bytes, String> {
+        let mut reader = BinaryReader::from(src);
+        let mut flag_positions = Vec::new();
+        BinarySerializer::find_truncated_floats(&mut reader, &mut flag_positions)?;
+
+        let mut upgraded = BytesMut::from(src);
+        for position in flag_positions {
+            upgraded[position] = TypeFlag::Int64.to_bin();
+        }
+        Ok(upgraded.freeze())
+    }
+
+    /// Moves the reader past a value and its flag like `skip_value`, adding the position of the flag of each TruncatedFloat in it.
+    fn find_truncated_floats(reader: &mut BinaryReader, flag_positions: &mut Vec<usize>) -> Result<(), String> {
+        let flag_position = reader.position;
+        match BinarySerializer::read_flag(reader)? {
+            TypeFlag::TruncatedFloat => {
+                flag_positions.push(flag_position);
+                Ok(reader.skip(8)?)
+            },
+            TypeFlag::Array => {
+                let count = reader.read_u64()?;
+                for _ in 0..count {
+                    BinarySerializer::find_truncated_floats(reader, flag_positions)?;
+                }
+                Ok(())
+            },
+            t @ (TypeFlag::Object | TypeFlag::IndexedObject) => {
+                let property_count = reader.read_u64()?;
+                if t == TypeFlag::IndexedObject {
+                    // the properties follow the size and the offset table, in the Object layout
+                    reader.skip(8)?;
+                    reader.skip(BinarySerializer::offset_table_size(property_count)?)?;
+                }
+                for _ in 0..property_count {
+                    reader.read_string_bytes()?;
+                    BinarySerializer::find_truncated_floats(reader, flag_positions)?;
+                }
+                Ok(())
+            },
+            TypeFlag::KeyedObject => {
+                let property_count = reader.read_varint()?;
+                reader.skip(8)?;
+                for _ in 0..property_count {
+                    reader.read_varint()?;
+                    BinarySerializer::find_truncated_floats(reader, flag_positions)?;
+                }
+                Ok(())
+            },
+            t => BinarySerializer::skip_value(t, reader)
+        }
+    }
+
+    /// Upgrades every document of the file with `upgrade_document`, through a compaction of the writer's file.
+    /// To run once on each file written before Float existed: until then, its floats are read truncated.
+    /// Fails with `StorageError::Corrupted` on a record which is not a document, and the file is left as it was.
+    pub fn upgrade_documents(writer: &mut DiskWriter) -> Result<CompactionReport, StorageError> {
+        writer.compact_rewriting(|record| {
+            let upgraded = BinarySerializer::upgrade_document(&record.content).map_err(|_| StorageError::Corrupted { position: record.position })?;
+            record.content = upgraded.to_vec();
+            Ok(())
+        })
+    }
+
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::disk_writer::tests::new_test_file;
 
     #[test]
     fn type_flag_to_bin_should_return_valid_value() -> Result<(), String> {
         assert_eq!(0, TypeFlag::Null.to_bin());
         assert_eq!(1, TypeFlag::Bool.to_bin());
         assert_eq!(2, TypeFlag::Int64.to_bin());
-        assert_eq!(3, TypeFlag::Float.to_bin());
+        assert_eq!(3, TypeFlag::TruncatedFloat.to_bin());
         assert_eq!(4, TypeFlag::Text.to_bin());
         assert_eq!(5, TypeFlag::Array.to_bin());
+        assert_eq!(7, TypeFlag::Float.to_bin());
+        assert_eq!(8, TypeFlag::UInt64.to_bin());
+        assert_eq!(9, TypeFlag::Decimal.to_bin());
+        assert_eq!(10, TypeFlag::IndexedObject.to_bin());
+        assert_eq!(11, TypeFlag::KeyedObject.to_bin());
+        assert_eq!(12, TypeFlag::SizedArray.to_bin());
+        assert_eq!(13, TypeFlag::SizedObject.to_bin());
 
         Ok(())
     }
 
     #[test]
     fn type_flag_from_bin_should_return_valid_value() -> Result<(), String> {
-        assert_eq!(TypeFlag::From(0).unwrap().to_bin(), TypeFlag::Null.to_bin());
-        assert_eq!(TypeFlag::From(1).unwrap().to_bin(), TypeFlag::Bool.to_bin());
-        assert_eq!(TypeFlag::From(2).unwrap().to_bin(), TypeFlag::Int64.to_bin());
-        assert_eq!(TypeFlag::From(3).unwrap().to_bin(), TypeFlag::Float.to_bin());
-        assert_eq!(TypeFlag::From(4).unwrap().to_bin(), TypeFlag::Text.to_bin());
-        assert_eq!(TypeFlag::From(5).unwrap().to_bin(), TypeFlag::Array.to_bin());
+        assert_eq!(TypeFlag::from(0).unwrap().to_bin(), TypeFlag::Null.to_bin());
+        assert_eq!(TypeFlag::from(1).unwrap().to_bin(), TypeFlag::Bool.to_bin());
+        assert_eq!(TypeFlag::from(2).unwrap().to_bin(), TypeFlag::Int64.to_bin());
+        assert_eq!(TypeFlag::from(3).unwrap().to_bin(), TypeFlag::TruncatedFloat.to_bin());
+        assert_eq!(TypeFlag::from(4).unwrap().to_bin(), TypeFlag::Text.to_bin());
+        assert_eq!(TypeFlag::from(5).unwrap().to_bin(), TypeFlag::Array.to_bin());
+        assert_eq!(TypeFlag::from(7).unwrap().to_bin(), TypeFlag::Float.to_bin());
+        assert_eq!(TypeFlag::from(8).unwrap().to_bin(), TypeFlag::UInt64.to_bin());
+        assert_eq!(TypeFlag::from(9).unwrap().to_bin(), TypeFlag::Decimal.to_bin());
+        assert_eq!(TypeFlag::from(10).unwrap().to_bin(), TypeFlag::IndexedObject.to_bin());
+        assert_eq!(TypeFlag::from(11).unwrap().to_bin(), TypeFlag::KeyedObject.to_bin());
+        assert_eq!(TypeFlag::from(12).unwrap().to_bin(), TypeFlag::SizedArray.to_bin());
+        assert_eq!(TypeFlag::from(13).unwrap().to_bin(), TypeFlag::SizedObject.to_bin());
+        assert!(TypeFlag::from(14).is_err());
 
         Ok(())
     }
@@ -315,8 +900,8 @@ mod tests {
         assert_eq!(doc["activated"], true);
         assert_eq!(doc["activated"], Value::Bool(true));
 
-        let v1 = serde_json::to_value(1234).or_else(|e| { Err(format!("cannot create a value : {}", e)) })?;
-        let v2 = serde_json::to_value(998).or_else(|e| { Err(format!("cannot create a value : {}", e)) })?;
+        let v1 = serde_json::to_value(1234).map_err(|e| format!("cannot create a value : {}", e))?;
+        let v2 = serde_json::to_value(998).map_err(|e| format!("cannot create a value : {}", e))?;
 
         assert_eq!(doc["messageIds"], Value::Array(vec![ v1, v2 ]));
 
@@ -411,4 +996,313 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn serialize_payload_with_floats_should_keep_exact_values() -> Result<(), String> {
+        let payload = r#"
+        {
+            "price": 19.99,
+            "latitude": 48.858370,
+            "longitude": -0.0,
+            "tiny": 5e-324,
+            "coordinates": [2.294481, 1.5]
+        }"#;
+        let bin:Bytes = BinarySerializer::serialize_json(&String::from(payload))?;
+        let doc = BinarySerializer::deserialize_json(&bin)?;
+
+        assert_eq!(doc, serde_json::from_str::<Value>(payload).unwrap());
+        assert_eq!(Some(19.99), doc["price"].as_f64());
+        assert_eq!((-0.0f64).to_bits(), doc["longitude"].as_f64().unwrap().to_bits());
+
+        Ok(())
+    }
+
+    #[test]
+    fn non_finite_floats_should_be_written_and_read_exactly() -> Result<(), String> {
+        let values = [f64::NAN, f64::from_bits(0xfff0_0000_0000_0001), f64::INFINITY, f64::NEG_INFINITY, -0.0];
+        let mut serializer = BinarySerializer::new();
+        for value in values {
+            serializer.serialize_f64(value);
+        }
+
+        let mut reader = BinaryReader::from(&serializer.writer.buffer);
+        for value in values {
+            assert_eq!(value.to_bits(), BinarySerializer::read_f64_value(&mut reader)?.to_bits());
+        }
+
+        let mut reader = BinaryReader::from(&serializer.writer.buffer);
+        let flag = TypeFlag::from(reader.read_u8()?)?;
+        assert_eq!(Value::Null, BinarySerializer::read_value(flag, &mut reader)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn document_with_truncated_floats_should_be_read_and_upgraded() -> Result<(), String> {
+        // {"price": 19.99} as written before Float existed
+        let mut wr = BinaryWriter::with_capacity(100);
+        wr.write_u8(TypeFlag::Object.to_bin());
+        wr.write_u64(1);
+        wr.write_string("price");
+        wr.write_u8(TypeFlag::TruncatedFloat.to_bin());
+        wr.write_i64(19);
+        let old = wr.buffer.freeze();
+
+        assert_eq!(BinarySerializer::deserialize_json(&old)?["price"], 19);
+
+        let upgraded = BinarySerializer::upgrade_document(&old)?;
+        assert_eq!(BinarySerializer::deserialize_json(&upgraded)?, BinarySerializer::deserialize_json(&old)?);
+        // object flag, property count, name length prefix and name
+        let flag_position = 1 + 8 + 8 + "price".len();
+        assert_eq!(TypeFlag::TruncatedFloat.to_bin(), old[flag_position]);
+        assert_eq!(TypeFlag::Int64.to_bin(), upgraded[flag_position]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn documents_of_a_file_should_be_upgraded() -> Result<(), String> {
+        use crate::storage::disk_reader::{DiskReader, DiskReaderOptions};
+        use crate::storage::disk_writer::tests::{new_test_file, read_contents};
+        use crate::storage::durability::DurabilityMode;
+
+        // {"prices": [19.99, 5.5]} as written before Float existed
+        let mut wr = BinaryWriter::with_capacity(100);
+        wr.write_u8(TypeFlag::Object.to_bin());
+        wr.write_u64(1);
+        wr.write_string("prices");
+        wr.write_u8(TypeFlag::Array.to_bin());
+        wr.write_u64(2);
+        for price in [19, 5] {
+            wr.write_u8(TypeFlag::TruncatedFloat.to_bin());
+            wr.write_i64(price);
+        }
+        let old = wr.buffer.freeze();
+        // a NaN is read as null, the current document must be kept byte for byte
+        let mut current = BinarySerializer::serialize_json_indexed(&String::from(r#"{"price": 19.99}"#))?.to_vec();
+        let float_position = current.len() - 9;
+        assert_eq!(TypeFlag::Float.to_bin(), current[float_position]);
+        current[float_position + 1..float_position + 9].copy_from_slice(&f64::NAN.to_bits().to_be_bytes());
+
+        let file_name = new_test_file("documents_of_a_file_should_be_upgraded");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.add_record(&old).unwrap();
+        writer.add_record(&current).unwrap();
+        writer.add_record(b"").unwrap();
+        assert!(matches!(BinarySerializer::upgrade_documents(&mut writer), Err(StorageError::Corrupted { .. })));
+        assert!(!std::path::Path::new(&writer.compaction_file_name()).exists());
+        assert_eq!(3, writer.meta.get().records_count);
+
+        let empty = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap().last().unwrap().unwrap().position;
+        writer.delete_record(empty).unwrap();
+        let report = BinarySerializer::upgrade_documents(&mut writer).unwrap();
+        assert_eq!(2, report.records_kept);
+
+        let contents = read_contents(&file_name, DiskReaderOptions::unlocked());
+        assert!(!contents[0].contains(&TypeFlag::TruncatedFloat.to_bin()));
+        assert_eq!(serde_json::json!({"prices": [19, 5]}), BinarySerializer::deserialize_json(&contents[0])?);
+        assert_eq!(current, contents[1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn serialize_payload_with_any_number_should_keep_exact_text() -> Result<(), String> {
+        let payload = r#"
+        {
+            "unsigned": 18446744073709551615,
+            "negative": -9223372036854775808,
+            "huge": 123456789012345678901234567890,
+            "precise": 0.1000000000000000000000000001,
+            "exponent": 1e3,
+            "negativeZero": -0,
+            "amounts": [12.50, 340282366920938463463374607431768211456]
+        }"#;
+        let bin:Bytes = BinarySerializer::serialize_json(&String::from(payload))?;
+        let doc = BinarySerializer::deserialize_json(&bin)?;
+
+        assert_eq!(doc, serde_json::from_str::<Value>(payload).unwrap());
+        assert_eq!(Some(u64::MAX), doc["unsigned"].as_u64());
+        assert_eq!(Some(i64::MIN), doc["negative"].as_i64());
+        // a `Value` holds the other numbers as f64, the serialized document keeps their text
+        let indexed = BinarySerializer::serialize_json_indexed(payload)?;
+        for text in ["123456789012345678901234567890", "0.1000000000000000000000000001", "1e3", "-0", "12.50", "340282366920938463463374607431768211456"] {
+            let mut decimal = vec![TypeFlag::Decimal.to_bin()];
+            decimal.extend_from_slice(&(text.len() as u64).to_be_bytes());
+            decimal.extend_from_slice(text.as_bytes());
+            assert!(bin.windows(decimal.len()).any(|w| w == decimal), "number {}", text);
+            assert!(indexed.windows(decimal.len()).any(|w| w == decimal), "number {}", text);
+        }
+        assert_eq!(TypeFlag::Float.to_bin(), BinarySerializer::serialize_json(r#"{ "amount": 12.5 }"#)?[1 + 8 + 8 + 8 + "amount".len()]);
+        assert!(BinarySerializer::serialize_json(r#"{ "outOfRange": 1e400 }"#).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_property_value_should_match_deserialized_document() -> Result<(), String> {
+        let payload = r#"
+        {
+            "name": "John Doe",
+            "nickname": null,
+            "age": 48,
+            "address": { "city": "Paris", "location": { "lat": 48.85837, "lon": 2.294481 } },
+            "messages": [
+                { "id": 1, "title": "hello !", "tags": ["a", "b"] },
+                { "id": 2, "text": "no title", "tags": [] },
+                [ { "title": "nested" }, 12, null ],
+                { "id": 4, "title": null, "meta": { "deleted": true } }
+            ],
+            "balance": 18446744073709551615
+        }"#;
+        let bin:Bytes = BinarySerializer::serialize_json(&String::from(payload))?;
+        let doc = serde_json::from_str::<Value>(payload).unwrap();
+
+        let paths = ["name", "nickname", "age", "address", "address.city", "address.location.lon", "messages", "messages.title",
+            "messages.tags", "messages.meta.deleted", "messages.id.missing", "balance", "missing", "name.first"];
+        for path in paths {
+            assert_eq!(crate::document::get_property_value(doc.clone(), String::from(path)),
+                BinarySerializer::get_property_value(&bin, path)?, "path {}", path);
+        }
+        assert_eq!(vec![Value::from("hello !"), Value::from("nested")], BinarySerializer::get_property_value(&bin, "messages.title")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn null_values_should_be_read_without_data() -> Result<(), String> {
+        let payload = r#"{ "first": null, "second": [null, 2], "third": "three" }"#;
+        let bin:Bytes = BinarySerializer::serialize_json(&String::from(payload))?;
+
+        assert_eq!(serde_json::from_str::<Value>(payload).unwrap(), BinarySerializer::deserialize_json(&bin)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn indexed_objects_should_be_read_like_plain_ones() -> Result<(), String> {
+        let payload = r#"
+        {
+            "name": "John Doe",
+            "nickname": null,
+            "age": 48,
+            "address": { "city": "Paris", "location": { "lat": 48.85837, "lon": 2.294481 } },
+            "messages": [
+                { "id": 1, "title": "hello !", "tags": ["a", "b"] },
+                { "id": 2, "text": "no title", "tags": [] },
+                [ { "title": "nested" }, 12, null ],
+                { "id": 4, "title": null, "meta": { "deleted": true } }
+            ],
+            "empty": {},
+            "balance": 18446744073709551615
+        }"#;
+        let plain:Bytes = BinarySerializer::serialize_json(&String::from(payload))?;
+        let indexed:Bytes = BinarySerializer::serialize_json_indexed(&String::from(payload))?;
+        assert_eq!(TypeFlag::IndexedObject.to_bin(), indexed[0]);
+
+        assert_eq!(serde_json::from_str::<Value>(payload).unwrap(), BinarySerializer::deserialize_json(&indexed)?);
+
+        let paths = ["name", "nickname", "age", "address", "address.city", "address.location.lon", "messages", "messages.title",
+            "messages.tags", "messages.meta.deleted", "messages.id.missing", "empty", "empty.missing", "balance", "missing"];
+        for path in paths {
+            assert_eq!(BinarySerializer::get_property_value(&plain, path)?, BinarySerializer::get_property_value(&indexed, path)?, "path {}", path);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn keyed_objects_should_be_skipped_without_reading_their_content() {
+        let file_name = new_test_file("keyed_objects_should_be_skipped_without_reading_their_content");
+        let mut keys = KeyDictionary::open(&file_name).unwrap();
+        let serialized = keys.serialize_json(r#"{ "a": { "b": 1, "c": 2 }, "d": "x" }"#).unwrap();
+        assert_eq!(TypeFlag::KeyedObject.to_bin(), serialized[0]);
+        // object header, then the id of the first property
+        let object_position = 1 + 1 + 8 + 1;
+        assert_eq!(TypeFlag::KeyedObject.to_bin(), serialized[object_position]);
+
+        let mut corrupted = serialized.to_vec();
+        corrupted[object_position + 1 + 1 + 8 + 1] = 0xFF;
+        assert_eq!(vec![Value::from("x")], keys.get_property_value(&corrupted, "d").unwrap());
+        assert!(keys.get_property_value(&corrupted, "a.b").is_err());
+    }
+
+    #[test]
+    fn sized_values_should_be_skipped_without_reading_their_content() -> Result<(), String> {
+        let serialized = BinarySerializer::serialize_json(&String::from(r#"{ "a": [1, 2, 3], "b": { "c": true }, "d": "x" }"#))?;
+        assert_eq!(TypeFlag::SizedObject.to_bin(), serialized[0]);
+        // object header, then the name of the first property
+        let array_position = 1 + 8 + 8 + 8 + 1;
+        assert_eq!(TypeFlag::SizedArray.to_bin(), serialized[array_position]);
+
+        let mut corrupted = serialized.to_vec();
+        for i in 0..3 {
+            corrupted[array_position + 1 + 8 + 8 + i * 9] = 0xFF;
+        }
+        assert_eq!(vec![Value::from("x")], BinarySerializer::get_property_value(&corrupted, "d")?);
+        assert_eq!(vec![Value::from(true)], BinarySerializer::get_property_value(&corrupted, "b.c")?);
+        assert!(BinarySerializer::get_property_value(&corrupted, "a").is_err());
+
+        // {"a": [1], "d": "x"} as written before sized values existed
+        let mut wr = BinaryWriter::with_capacity(100);
+        wr.write_u8(TypeFlag::Object.to_bin());
+        wr.write_u64(2);
+        wr.write_string("a");
+        wr.write_u8(TypeFlag::Array.to_bin());
+        wr.write_u64(1);
+        wr.write_u8(TypeFlag::Int64.to_bin());
+        wr.write_i64(1);
+        wr.write_string("d");
+        wr.write_u8(TypeFlag::Text.to_bin());
+        wr.write_string("x");
+        let old = wr.buffer.freeze();
+        assert_eq!(serde_json::json!({"a": [1], "d": "x"}), BinarySerializer::deserialize_json(&old)?);
+        assert_eq!(vec![Value::from("x")], BinarySerializer::get_property_value(&old, "d")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn indexed_object_lookup_should_tell_apart_names_with_same_hash() -> Result<(), String> {
+        assert_eq!(crc32fast::hash(b"plumless"), crc32fast::hash(b"buckeroo"));
+        let payload = r#"{ "plumless": 1, "buckeroo": 2, "other": { "buckeroo": [3] } }"#;
+        let indexed:Bytes = BinarySerializer::serialize_json_indexed(&String::from(payload))?;
+
+        assert_eq!(vec![Value::from(1)], BinarySerializer::get_property_value(&indexed, "plumless")?);
+        assert_eq!(vec![Value::from(2)], BinarySerializer::get_property_value(&indexed, "buckeroo")?);
+        assert_eq!(vec![Value::from(vec![3])], BinarySerializer::get_property_value(&indexed, "other.buckeroo")?);
+        assert!(BinarySerializer::get_property_value(&indexed, "other.plumless")?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_indexed_object_should_be_rejected() -> Result<(), String> {
+        let indexed = BinarySerializer::serialize_json_indexed(&String::from(r#"{ "name": "John Doe", "age": 48 }"#))?;
+        let corrupt = |position: usize, value: u64| {
+            let mut corrupted = indexed.to_vec();
+            corrupted[position..position + 8].copy_from_slice(&value.to_be_bytes());
+            corrupted
+        };
+        let (count_position, size_position) = (1, 1 + 8);
+        let offset_position = 1 + 8 + 8 + 4;
+
+        // the entries of the offset table are sorted by hash
+        let mut names = ["name", "age"];
+        names.sort_by_key(|name| crc32fast::hash(name.as_bytes()));
+
+        let cases = [
+            (corrupt(count_position, u64::MAX), "name"), (corrupt(count_position, u64::MAX), "age"),
+            (corrupt(count_position, u64::MAX / PROPERTY_ENTRY_SIZE as u64 + 1), "name"), (corrupt(count_position, u64::MAX / PROPERTY_ENTRY_SIZE as u64 + 1), "age"),
+            (corrupt(size_position, u64::MAX), "name"), (corrupt(size_position, u64::MAX), "age"),
+            (corrupt(size_position, 1 << 20), "name"), (corrupt(size_position, 1 << 20), "age"),
+            (corrupt(offset_position, u64::MAX), names[0]), (corrupt(offset_position + PROPERTY_ENTRY_SIZE, u64::MAX - 100), names[1])
+        ];
+        for (i, (corrupted, path)) in cases.iter().enumerate() {
+            assert!(BinarySerializer::get_property_value(corrupted, path).is_err(), "case {} path {}", i, path);
+        }
+        assert!(BinarySerializer::deserialize_json(&corrupt(count_position, u64::MAX)).is_err());
+
+        Ok(())
+    }
+
 }
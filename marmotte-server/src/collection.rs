@@ -0,0 +1,115 @@
+use serde_json::Value;
+
+use crate::binary_serializer::JsonDocument;
+use crate::key_dictionary::KeyDictionary;
+use crate::storage::disk_reader::DiskReaderOptions;
+use crate::storage::disk_writer::DiskWriter;
+use crate::storage::durability::DurabilityMode;
+use crate::storage::error::StorageError;
+use crate::storage::shared::SharedFile;
+
+/// Documents of a collection, in a records file, with the `KeyDictionary` of their property names next to it.
+///
+/// Documents are written as KeyedObjects: the names a document adds to the dictionary are committed before the document,
+/// and the dictionary is needed to read them back. Both files are locked by their writer while the collection is open.
+///
+/// Documents are read through a snapshot of the `SharedFile`, which sees every document inserted so far,
+/// whether or not the durability mode already committed it to disk.
+pub struct Collection {
+    file: SharedFile,
+    keys: KeyDictionary
+}
+
+impl Collection {
+
+    /// Opens the collection and its dictionary, or creates both if the file does not exist.
+    pub fn open(file_name: &str, page_size: u64, durability: DurabilityMode) -> Result<Collection, StorageError> {
+        let file = SharedFile::new(DiskWriter::new(file_name, page_size, durability)?);
+        let keys = KeyDictionary::open(&KeyDictionary::file_name_of(file_name))?;
+        Ok(Collection { file, keys })
+    }
+
+    pub fn keys(&self) -> &KeyDictionary {
+        &self.keys
+    }
+
+    /// Adds the document and returns its position.
+    pub fn insert(&mut self, json: &str) -> Result<u64, StorageError> {
+        let document = JsonDocument::parse(json).map_err(|e| StorageError::InvalidDocument(e.to_string()))?;
+        let document = self.keys.serialize_document(document, json.len())?;
+        self.file.add_record(&document)
+    }
+
+    pub fn delete(&mut self, position: u64) -> Result<(), StorageError> {
+        self.file.delete_record(position)
+    }
+
+    /// Reads the document at `position`, as returned by `insert`.
+    pub fn get(&self, position: u64) -> Result<Value, StorageError> {
+        let content = self.read_document(position)?;
+        self.keys.deserialize_json(&content).map_err(|_| StorageError::Corrupted { position })
+    }
+
+    /// Returns the values at `path` in the document at `position`, see `BinarySerializer::get_property_value`.
+    pub fn get_property_value(&self, position: u64, path: &str) -> Result<Vec<Value>, StorageError> {
+        let content = self.read_document(position)?;
+        self.keys.get_property_value(&content, path).map_err(|_| StorageError::Corrupted { position })
+    }
+
+    fn read_document(&self, position: u64) -> Result<Vec<u8>, StorageError> {
+        let mut reader = self.file.snapshot(DiskReaderOptions::unlocked())?;
+        let record = reader.read_record_at(position)?;
+        if record.deleted {
+            return Err(StorageError::InvalidPosition(position));
+        }
+        Ok(record.content)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Duration;
+    use super::*;
+    use crate::binary_serializer::BinarySerializer;
+    use crate::storage::disk_reader::DiskReader;
+    use crate::storage::disk_writer::tests::new_test_file;
+
+    #[test]
+    fn documents_should_be_written_with_the_keys_of_the_collection() {
+        let file_name = new_test_file("documents_should_be_written_with_the_keys_of_the_collection");
+        let mut collection = Collection::open(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        assert!(Path::new(&KeyDictionary::file_name_of(&file_name)).exists());
+
+        let first = collection.insert(r#"{ "id": 1, "customer": { "name": "John Doe" }, "tags": ["a", "b"] }"#).unwrap();
+        let second = collection.insert(r#"{ "id": 2, "customer": { "name": "Jane Doe", "vip": true } }"#).unwrap();
+        assert!(matches!(collection.insert("{ not json"), Err(StorageError::InvalidDocument(_))));
+        assert_eq!(5, collection.keys().len());
+
+        let record = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap().read_record_at(first).unwrap();
+        assert!(BinarySerializer::deserialize_json(&record.content).is_err());
+        assert!(matches!(Collection::open(&file_name, 2048, DurabilityMode::EveryWrite), Err(StorageError::Locked(_))));
+
+        collection.delete(first).unwrap();
+        assert!(matches!(collection.get(first), Err(StorageError::InvalidPosition(_))));
+        drop(collection);
+
+        let collection = Collection::open(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        assert_eq!(serde_json::json!({ "id": 2, "customer": { "name": "Jane Doe", "vip": true } }), collection.get(second).unwrap());
+        assert_eq!(vec![Value::from(true)], collection.get_property_value(second, "customer.vip").unwrap());
+    }
+
+    #[test]
+    fn inserted_document_should_be_read_before_its_group_commit() {
+        let file_name = new_test_file("inserted_document_should_be_read_before_its_group_commit");
+        let durability = DurabilityMode::GroupCommit { max_delay: Duration::from_secs(60), max_batch: 1000 };
+        let mut collection = Collection::open(&file_name, 2048, durability).unwrap();
+
+        let position = collection.insert(r#"{ "id": 1, "customer": { "name": "John Doe" } }"#).unwrap();
+        assert!(DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap().read_record_at(position).is_err());
+        assert_eq!(serde_json::json!({ "id": 1, "customer": { "name": "John Doe" } }), collection.get(position).unwrap());
+        assert_eq!(vec![Value::from("John Doe")], collection.get_property_value(position, "customer.name").unwrap());
+    }
+
+}
@@ -1 +1 @@
-﻿mod sorted_index_table;
\ No newline at end of file
+﻿pub mod sorted_index_table;
\ No newline at end of file
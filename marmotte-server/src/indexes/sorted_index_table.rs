@@ -1,11 +1,7 @@
-﻿use std::cmp::Ordering;
-use std::fs::{exists, File, OpenOptions};
+﻿use std::fs::{exists, File, OpenOptions};
 use std::io;
-use std::io::{Read, Seek, Write};
-use std::mem::{self, size_of};
-use bytes::BytesMut;
-use crate::binary::{BinaryReader, BinaryWriter};
-use crate::storage::disk_writer::RecordsFileMeta;
+use std::io::{Seek, Write};
+use std::mem::size_of;
 
 pub struct FenseIndex<T: Ord> {
     pub active: bool,
@@ -48,10 +44,6 @@ impl SortedIndexTableFragmentHeader {
     }
 }
 
-type ValueReader<T> = fn(BinaryReader) -> Result<T, String>;
-
-type ValueWriter<T> = fn(&mut BinaryWriter, T) -> Result<(), String>;
-
 impl SortedIndexFiles {
 
     pub fn new_with_defaults(folder: String) -> Result<Self, String> {
@@ -86,6 +78,7 @@ impl SortedIndexFiles {
         let file = OpenOptions::new()
             .append(false)
             .create(true)
+            .truncate(false)
             .read(true)
             .write(true)
             .open(file_name)
@@ -117,96 +110,10 @@ impl SortedIndexFiles {
         let handles = self.write_handles.as_mut_slice();
         let file = &mut handles[num];
         file.seek(io::SeekFrom::Start(0)).map_err(|e| e.to_string()).map_err(|e| e.to_string())?;
-        file.write(&header.max_records_count.to_le_bytes()).map_err(|e| e.to_string())?;
-        file.write(&header.shift_threshold.to_le_bytes()).map_err(|e| e.to_string())?;
+        file.write_all(&header.max_records_count.to_le_bytes()).map_err(|e| e.to_string())?;
+        file.write_all(&header.shift_threshold.to_le_bytes()).map_err(|e| e.to_string())?;
         Ok(())
     }
-
-    fn read_header(&mut self, num: usize) -> Result<SortedIndexTableFragmentHeader, String> {
-        let mut max_records_count = [0u8; 4];
-        let mut shift_threshold = [0u8; 4];
-
-        let handles = self.write_handles.as_mut_slice();
-        let file = &mut handles[num];
-
-        file.seek(io::SeekFrom::Start(0)).map_err(|e| e.to_string()).map_err(|e| e.to_string())?;
-        file.read(&mut max_records_count).map_err(|e| e.to_string())?;
-        file.read(&mut shift_threshold).map_err(|e| e.to_string())?;
-
-        Ok(SortedIndexTableFragmentHeader {
-            max_records_count: u32::from_le_bytes(max_records_count),
-            shift_threshold: u32::from_le_bytes(shift_threshold),
-        })
-    }
-
-    fn read_offset<T: Ord>(&mut self, num: usize, offset: u32, read_value: ValueReader<T>) -> Result<FenseIndex<T>, String> {
-        let after_header_offset_position = SortedIndexTableFragmentHeader::get_binary_size() as u64;
-        let offset_position = after_header_offset_position + (offset as u64) * FenseIndex::<T>::get_binary_size() as u64;
-
-        let handles = self.write_handles.as_mut_slice();
-        let file = &mut handles[num];
-        file.seek(io::SeekFrom::Start(offset_position)).map_err(|e| e.to_string()).map_err(|e| e.to_string())?;
-
-        let mut buf = vec![0; FenseIndex::<T>::get_binary_size()];
-        file.read(&mut buf).unwrap();
-        let bytes = BytesMut::from(buf.as_slice());
-        let mut bin = BinaryReader::from(bytes);
-
-        let active = bin.read_bool()?;
-        let target = bin.read_u64()?;
-        let value = read_value(bin)?;
-
-        Ok(FenseIndex {
-            active,
-            target,
-            value
-        })
-    }
-
-    fn read_all_indexes<T: Ord>(&mut self, num: usize, offset: u32, read_value: ValueReader<T>) -> Result<Vec<FenseIndex<T>>, String> {
-        let after_header_offset_position = SortedIndexTableFragmentHeader::get_binary_size() as u64;
-        let offset_position = after_header_offset_position + (offset as u64) * FenseIndex::<T>::get_binary_size() as u64;
-
-        let handles = self.write_handles.as_mut_slice();
-        let file = &mut handles[num];
-        file.seek(io::SeekFrom::Start(offset_position)).map_err(|e| e.to_string()).map_err(|e| e.to_string())?;
-
-        let mut items = Vec::with_capacity(self.max_records_count_per_fragments as usize);
-        for _ in 0..self.max_records_count_per_fragments {
-
-            let mut buf = vec![0; FenseIndex::<T>::get_binary_size()];
-            file.read(&mut buf).unwrap();
-            let bytes = BytesMut::from(buf.as_slice());
-            let mut bin = BinaryReader::from(bytes);
-
-            let active = bin.read_bool()?;
-            let target = bin.read_u64()?;
-            let value = read_value(bin)?;
-
-            items.push(FenseIndex { active, target, value });
-        }
-
-        Ok(items)
-    }
-
-    fn write_offset<T: Ord>(&mut self, num: usize, ix: FenseIndex<T>, offset: u32, write_value: ValueWriter<T>) -> Result<(), String> {
-        let after_header_offset_position = SortedIndexTableFragmentHeader::get_binary_size() as u64;
-        let offset_position = after_header_offset_position + (offset as u64) * FenseIndex::<T>::get_binary_size() as u64;
-
-        let handles = self.write_handles.as_mut_slice();
-        let file = &mut handles[num];
-        file.seek(io::SeekFrom::Start(offset_position)).map_err(|e| e.to_string()).map_err(|e| e.to_string())?;
-
-        let mut bin = BinaryWriter::with_capacity(FenseIndex::<T>::get_binary_size());
-        bin.write_bool(true);
-        bin.write_u64(ix.target);
-
-        write_value(&mut bin, ix.value)?;
-
-        let content = bin.buffer.freeze().to_vec();
-        
-        file.write_all(&content).map_err(|e| e.to_string())
-    }
 }
 
 pub struct SortedIndexTableFragment {
@@ -223,51 +130,9 @@ impl SortedIndexTableFragment {
 
 #[cfg(test)]
 mod tests {
+    use std::cmp::Ordering;
     use super::*;
 
-    #[test]
-    fn should_read_only_written_index_records() {
-        let folder = "test_folder/should_read_only_written_index_records";
-        if std::fs::exists(folder).unwrap() {
-            std::fs::remove_dir_all(folder).unwrap();
-        }
-
-        let mut files = SortedIndexFiles::new(folder.to_string(), 3, 10, 500).unwrap();
-        files.open_fragment::<String>(0).unwrap();
-
-        for i in 20u32..30u32 {
-            let item: FenseIndex<String> = FenseIndex { active: true, target: (100 * i as u64), value: format!("string value {i}") };
-            files.write_offset(0, item, i, move |bin, v| Ok(bin.write_string(v.as_str()))).unwrap();
-        }
-
-        let fetched_records = files.read_all_indexes(0, 0, move |mut bin| Ok(bin.read_string().unwrap())).unwrap();
-        let stored_values = fetched_records.iter().filter(|r| r.active).map(|r| r.value.clone()).collect::<Vec<String>>();
-
-        assert_eq!(10, stored_values.len());
-        assert_eq!("string value 20", stored_values[0]);
-        assert_eq!("string value 21", stored_values[1]);
-        assert_eq!("string value 22", stored_values[2]);
-        assert_eq!("string value 29", stored_values[9]);
-    }
-
-    #[test]
-    fn should_write_and_read_index_file_header() {
-        let folder = "test_folder/should_write_and_read_index_file_header";
-
-        if std::fs::exists(folder).unwrap() {
-            std::fs::remove_dir_all(folder).unwrap();
-        }
-
-        let mut files = SortedIndexFiles::new(folder.to_string(), 3, 10, 50).unwrap();
-
-        files.open_fragment::<String>(0).unwrap();
-
-        let header = files.read_header(0).unwrap();
-
-        assert_eq!(50, header.max_records_count);
-        assert_eq!(10, header.shift_threshold);
-    }
-
     #[test]
     fn string_index_should_be_greater() {
         // given
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use serde_json::Value;
+
+use crate::binary::{BinaryReader, BinaryWriter};
+use crate::binary_serializer::{BinarySerializer, JsonDocument};
+use crate::storage::disk_reader::{DiskReader, DiskReaderOptions};
+use crate::storage::disk_writer::DiskWriter;
+use crate::storage::durability::DurabilityMode;
+use crate::storage::error::StorageError;
+
+/// Page size of dictionary files, they only hold property names.
+const KEY_DICTIONARY_PAGE_SIZE: u64 = 4096;
+
+/// Property names of the documents of a collection and their ids, written by `BinarySerializer` in place of the names.
+///
+/// The dictionary is persisted in a records file next to the collection, see `file_name_of`. Each record holds the names
+/// added at once, and a name's id is its rank in the file, so ids never change. A record is committed as a whole:
+/// a crash while names are added leaves all or none of them, and they are durable before any document using them is written.
+/// The writer locks the file, a single dictionary of a collection is open at a time. `Collection` opens it with its collection.
+pub struct KeyDictionary {
+    writer: DiskWriter,
+    names: Vec<String>,
+    ids: HashMap<String, u64>
+}
+
+impl KeyDictionary {
+
+    pub fn file_name_of(collection_file_name: &str) -> String {
+        format!("{}.keys", collection_file_name)
+    }
+
+    /// Opens the dictionary, or creates an empty one if the file does not exist.
+    pub fn open(file_name: &str) -> Result<KeyDictionary, StorageError> {
+        let writer = DiskWriter::new(file_name, KEY_DICTIONARY_PAGE_SIZE, DurabilityMode::EveryWrite)?;
+        let mut dictionary = KeyDictionary { writer, names: Vec::new(), ids: HashMap::new() };
+
+        // the reader does not lock the file, the writer holds an exclusive lock on it
+        for record in DiskReader::new(file_name, DiskReaderOptions::unlocked())? {
+            let record = record?;
+            let mut reader = BinaryReader::from(&record.content);
+            while !reader.end() {
+                let name = reader.read_string().map_err(|_| StorageError::Corrupted { position: record.position })?;
+                dictionary.insert(name);
+            }
+        }
+        Ok(dictionary)
+    }
+
+    fn insert(&mut self, name: String) {
+        self.ids.insert(name.clone(), self.names.len() as u64);
+        self.names.push(name);
+    }
+
+    pub fn id_of(&self, name: &str) -> Option<u64> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn name_of(&self, id: u64) -> Option<&str> {
+        self.names.get(id as usize).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Adds the names not in the dictionary yet, in a single record.
+    pub fn add_keys<'n, I>(&mut self, names: I) -> Result<(), StorageError> where I: IntoIterator<Item = &'n str> {
+        let mut new_names: Vec<&str> = Vec::new();
+        for name in names {
+            if self.id_of(name).is_none() && !new_names.contains(&name) {
+                new_names.push(name);
+            }
+        }
+        if new_names.is_empty() {
+            return Ok(());
+        }
+
+        let mut bin = BinaryWriter::with_capacity(new_names.iter().map(|name| 8 + name.len()).sum());
+        for name in &new_names {
+            bin.write_string(name);
+        }
+        self.writer.add_record(&bin.buffer)?;
+
+        for name in new_names {
+            self.insert(String::from(name));
+        }
+        Ok(())
+    }
+
+    /// Serializes the document with its property names written as ids, after adding the new names to the dictionary.
+    pub fn serialize_json(&mut self, json: &str) -> Result<Bytes, String> {
+        let document = JsonDocument::parse(json).map_err(|_| String::from("Could not parse JSON"))?;
+        self.serialize_document(document, json.len()).map_err(|e| e.to_string())
+    }
+
+    /// Serializes the document like `serialize_json`, in a buffer of `capacity` bytes to start with.
+    pub fn serialize_document(&mut self, document: JsonDocument, capacity: usize) -> Result<Bytes, StorageError> {
+        let mut names = Vec::new();
+        KeyDictionary::collect_names(&document.value, &mut names);
+        self.add_keys(names)?;
+
+        let wr = BinaryWriter::with_capacity(capacity);
+        let mut serializer = BinarySerializer { writer: Box::new(wr), indexed_objects: false, keys: Some(self) };
+        serializer.serialize_document(document, capacity).map_err(|e| StorageError::InvalidDocument(String::from(e)))?;
+        Ok(serializer.writer.buffer.freeze())
+    }
+
+    fn collect_names<'v>(value: &'v Value, names: &mut Vec<&'v str>) {
+        match value {
+            Value::Object(o) => {
+                for (key, value) in o {
+                    names.push(key);
+                    KeyDictionary::collect_names(value, names);
+                }
+            },
+            Value::Array(items) => {
+                for item in items {
+                    KeyDictionary::collect_names(item, names);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    pub fn deserialize_json(&self, src: &[u8]) -> Result<Value, String> {
+        let mut reader = BinaryReader::from(src);
+        BinarySerializer::read_json_object_with_keys(&mut reader, Some(self))
+    }
+
+    /// Returns the values at `path` in a document serialized with this dictionary, see `BinarySerializer::get_property_value`.
+    pub fn get_property_value(&self, src: &[u8], path: &str) -> Result<Vec<Value>, String> {
+        BinarySerializer::get_property_value_with_keys(src, path, Some(self))
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::disk_writer::tests::new_test_file;
+
+    fn payload(i: usize) -> String {
+        format!(r#"
+        {{
+            "customerIdentifier": "customer-{}",
+            "shippingAddress": {{ "streetName": "rue de Rivoli", "postalCode": "75001", "location": {{ "latitude": 48.85837, "longitude": 2.294481 }} }},
+            "orderLines": [
+                {{ "productReference": "ref-1", "quantity": {}, "unitPrice": 19.99 }},
+                {{ "productReference": "ref-2", "quantity": 1, "unitPrice": 5.5, "giftWrapping": true }}
+            ],
+            "deliveryInstructions": null
+        }}"#, i, i)
+    }
+
+    #[test]
+    fn keyed_documents_should_be_smaller_and_read_back() {
+        let file_name = new_test_file("keyed_documents_should_be_smaller_and_read_back");
+        let mut keys = KeyDictionary::open(&file_name).unwrap();
+        assert!(keys.is_empty());
+
+        let keyed = keys.serialize_json(&payload(1)).unwrap();
+        let plain = BinarySerializer::serialize_json(&payload(1)).unwrap();
+        assert!(keyed.len() * 2 < plain.len());
+        assert_eq!(serde_json::from_str::<Value>(&payload(1)).unwrap(), keys.deserialize_json(&keyed).unwrap());
+        assert!(BinarySerializer::deserialize_json(&keyed).is_err());
+
+        for path in ["customerIdentifier", "shippingAddress.location.latitude", "orderLines.unitPrice", "orderLines.giftWrapping",
+            "orderLines", "deliveryInstructions", "missing", "orderLines.missing"] {
+            assert_eq!(BinarySerializer::get_property_value(&plain, path).unwrap(), keys.get_property_value(&keyed, path).unwrap(), "path {}", path);
+        }
+
+        // the offset table of an IndexedObject holds hashes of names, not ids
+        let mut serializer = BinarySerializer { writer: Box::new(BinaryWriter::with_capacity(0)), indexed_objects: true, keys: Some(&keys) };
+        assert!(serializer.serialize_json_value(&serde_json::from_str::<Value>(&payload(1)).unwrap(), 0).is_err());
+
+        // names are added once, all at once
+        let names = keys.len();
+        assert_eq!(1, keys.writer.meta.get().records_count);
+        keys.serialize_json(&payload(2)).unwrap();
+        assert_eq!(names, keys.len());
+        assert_eq!(1, keys.writer.meta.get().records_count);
+    }
+
+    #[test]
+    fn key_dictionary_should_be_persisted() {
+        let file_name = new_test_file("key_dictionary_should_be_persisted");
+        let mut keys = KeyDictionary::open(&file_name).unwrap();
+        let first = keys.serialize_json(&payload(1)).unwrap();
+        let second = keys.serialize_json(r#"{ "customerIdentifier": "customer-2", "loyaltyPoints": 120 }"#).unwrap();
+        let loyalty_points = keys.id_of("loyaltyPoints").unwrap();
+        assert!(matches!(KeyDictionary::open(&file_name), Err(StorageError::Locked(_))));
+        drop(keys);
+
+        let keys = KeyDictionary::open(&file_name).unwrap();
+        assert_eq!(Some(loyalty_points), keys.id_of("loyaltyPoints"));
+        assert_eq!(Some("loyaltyPoints"), keys.name_of(loyalty_points));
+        assert_eq!(serde_json::from_str::<Value>(&payload(1)).unwrap(), keys.deserialize_json(&first).unwrap());
+        assert_eq!(vec![Value::from(120)], keys.get_property_value(&second, "loyaltyPoints").unwrap());
+    }
+
+}
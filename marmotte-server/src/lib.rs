@@ -0,0 +1,7 @@
+pub mod binary;
+pub mod binary_serializer;
+pub mod key_dictionary;
+pub mod collection;
+pub mod storage;
+pub mod document;
+pub mod indexes;
@@ -1,14 +1,9 @@
-mod binary;
-mod binary_serializer;
-mod storage;
-mod document;
-mod indexes;
-
 use std::time::Instant;
 
 use figlet_rs::FIGfont;
-use storage::disk_writer::DiskWriter;
-use storage::disk_reader::{DiskReader, DiskReaderOptions};
+use marmotte_server::storage::disk_writer::DiskWriter;
+use marmotte_server::storage::durability::DurabilityMode;
+use marmotte_server::storage::disk_reader::{DiskReader, DiskReaderOptions};
 
 fn main() {
     let font = FIGfont::standard().unwrap();
@@ -16,8 +11,8 @@ fn main() {
     assert!(figure.is_some());
     println!("{}", figure.unwrap());
 
-    let mut data_writer_1 = DiskWriter::new("test1.data", 2048);
-    let mut data_writer_2 = DiskWriter::new("test2.data", 2048);
+    let mut data_writer_1 = DiskWriter::new("test1.data", 2048, DurabilityMode::EveryWrite).unwrap();
+    let mut data_writer_2 = DiskWriter::new("test2.data", 2048, DurabilityMode::EveryWrite).unwrap();
 
     let mut iter_start = Instant::now();
 
@@ -34,7 +29,7 @@ fn main() {
 
         if i % batch_size == 0 {
             let references: Vec<&[u8]> = batch.iter().map(|v| v.as_slice()).collect();
-            data_writer_1.bulk_add_records(references);
+            data_writer_1.bulk_add_records(references).unwrap();
 
             batch.clear();
 
@@ -50,7 +45,7 @@ fn main() {
 
     for i in 1..bench_length+1 {
 
-        data_writer_2.add_record(format!("Record number {}!", i).as_bytes());
+        data_writer_2.add_record(format!("Record number {}!", i).as_bytes()).unwrap();
 
         if i % batch_size == 0 {
             println!("Wrote {} records", i);
@@ -65,19 +60,19 @@ fn main() {
 
     println!("Starting to read records...");
 
-    let mut data_reader_1 = DiskReader::new("test1.data", DiskReaderOptions::create_default());
-    let mut data_reader_2 = DiskReader::new("test2.data", DiskReaderOptions::create_default());
+    let data_reader_1 = DiskReader::new("test1.data", DiskReaderOptions::unlocked()).unwrap();
+    let data_reader_2 = DiskReader::new("test2.data", DiskReaderOptions::unlocked()).unwrap();
 
     println!("Reading records of test1.data ...");
 
     for item in data_reader_1 {
-        let text = String::from_utf8(item.content).unwrap();
+        let text = String::from_utf8(item.unwrap().content).unwrap();
         println!("{}", text);
     }
 
     println!("Reading records of test2.data ...");
     for item in data_reader_2 {
-        let text = String::from_utf8(item.content).unwrap();
+        let text = String::from_utf8(item.unwrap().content).unwrap();
         println!("{}", text);
     }
 
@@ -0,0 +1,282 @@
+use std::fs::File;
+use std::path::Path;
+use crate::storage::disk_reader::{DiskReader, DiskReaderOptions};
+use crate::storage::disk_writer::{DiskWriter, Record, RecordsFileMeta};
+use crate::storage::durability::DurabilityMode;
+use crate::storage::encryption::EncryptionKey;
+use crate::storage::free_space::FreeSpaceMap;
+use crate::storage::sparse_index::SparseIndex;
+use crate::storage::error::StorageError;
+
+/// Number of live records copied per bulk write while compacting or upgrading a file.
+pub(crate) const COPY_BATCH_SIZE: usize = 1000;
+
+pub struct CompactionReport {
+    pub records_kept: u64,
+    pub records_removed: u64,
+    pub bytes_reclaimed: u64
+}
+
+impl DiskWriter {
+
+    pub fn compaction_file_name(&self) -> String {
+        format!("{}.compact", self.file_name)
+    }
+
+    /// Rewrites the live records into a new file and swaps it in place of the current one.
+    ///
+    /// The new file is fully written and synced under a temporary name before being renamed over the
+    /// original, so a crash at any point leaves either the old or the new file, never a mix of both.
+    /// Positions of the records change, indexes pointing to them must be rebuilt.
+    pub fn compact(&mut self) -> Result<CompactionReport, StorageError> {
+        self.compact_with_key(self.encryption_key)
+    }
+
+    /// Compacts the file, encrypting the new file with `key`, or writing it in clear if `key` is `None`.
+    /// This is how keys are rotated: the writer uses `key` afterwards.
+    pub fn compact_with_key(&mut self, key: Option<EncryptionKey>) -> Result<CompactionReport, StorageError> {
+        self.compact_records(key, &mut |_| Ok(()))
+    }
+
+    /// Compacts the file, passing each live record to `rewrite` before it is copied: the new file holds the contents
+    /// `rewrite` left in the records. This is how records written in an older encoding of their content are converted.
+    pub fn compact_rewriting<F>(&mut self, mut rewrite: F) -> Result<CompactionReport, StorageError> where F: FnMut(&mut Record) -> Result<(), StorageError> {
+        self.compact_records(self.encryption_key, &mut rewrite)
+    }
+
+    fn compact_records(&mut self, key: Option<EncryptionKey>, rewrite: &mut dyn FnMut(&mut Record) -> Result<(), StorageError>) -> Result<CompactionReport, StorageError> {
+        let compaction_file_name = self.compaction_file_name();
+        if Path::new(&compaction_file_name).exists() {
+            // leftover of a compaction interrupted before the swap
+            std::fs::remove_file(&compaction_file_name)?;
+        }
+
+        // the reader below only sees committed metadata
+        self.stop_group_committer()?;
+
+        let old_len = self.file.metadata()?.len();
+        let copied = self.copy_live_records(&compaction_file_name, key, rewrite)
+            .and_then(|(mut file, records_kept, records_removed)| {
+                let meta = RecordsFileMeta::read_metadata(&mut file)?;
+                // the new file has no deleted record, and forgetting the free space of the current one loses no record
+                FreeSpaceMap::empty(&self.free_space.file_name).save()?;
+                std::fs::rename(&compaction_file_name, &self.file_name)?;
+                Ok((file, meta, records_kept, records_removed))
+            });
+        let (file, meta, records_kept, records_removed) = match copied {
+            Ok(copied) => copied,
+            Err(e) => {
+                // the current file is left as it was
+                let _ = std::fs::remove_file(&compaction_file_name);
+                self.start_group_committer()?;
+                return Err(e);
+            }
+        };
+
+        // the writer writes to the new file from now on, even when one of the steps below fails
+        self.file = file;
+        self.meta.set(meta);
+        self.encryption_key = key;
+        self.free_space = FreeSpaceMap::empty(&self.free_space.file_name);
+        self.sparse_index = SparseIndex::empty(&self.sparse_index.file_name);
+        sync_parent_folder(&self.file_name);
+
+        let swapped = self.swap_sparse_index(&compaction_file_name);
+        self.start_group_committer()?;
+        swapped?;
+
+        let new_len = self.file.metadata()?.len();
+
+        Ok(CompactionReport {
+            records_kept,
+            records_removed,
+            bytes_reclaimed: old_len.saturating_sub(new_len)
+        })
+    }
+
+    /// Replaces the sparse index of the current file by the one written along with the compaction file.
+    fn swap_sparse_index(&mut self, compaction_file_name: &str) -> Result<(), StorageError> {
+        // a crash before the index is renamed leaves the old one, whose checkpoints readers reject
+        let index_file_name = SparseIndex::file_name_of(compaction_file_name);
+        if Path::new(&index_file_name).exists() {
+            std::fs::rename(&index_file_name, &self.sparse_index.file_name)?;
+        } else {
+            std::fs::write(&self.sparse_index.file_name, [])?;
+        }
+        self.sparse_index = SparseIndex::load(&self.sparse_index.file_name)?;
+        Ok(())
+    }
+
+    /// Copies the live records into a new file named `compaction_file_name`, returning it with the numbers of records kept and removed.
+    fn copy_live_records(&self, compaction_file_name: &str, key: Option<EncryptionKey>, rewrite: &mut dyn FnMut(&mut Record) -> Result<(), StorageError>) -> Result<(File, u64, u64), StorageError> {
+        let mut records_kept = 0;
+        let mut records_removed = 0;
+
+        let options = DiskReaderOptions { encryption_key: self.encryption_key, ..DiskReaderOptions::unlocked() };
+        let mut reader = DiskReader::new(&self.file_name, options)?;
+
+        let mut target = DiskWriter::new_with_key(compaction_file_name, self.page_size, DurabilityMode::OsBuffered, key)?;
+        target.compression = self.compression;
+        // the LSNs of the removed records are not given again
+        target.raise_last_lsn(self.meta.get().last_lsn)?;
+        let mut batch: Vec<Record> = Vec::with_capacity(COPY_BATCH_SIZE);
+
+        if self.meta.get().reused_space {
+            // the records are copied in LSN order, the new file is in commit order again
+            let mut live = Vec::new();
+            for record in reader.by_ref() {
+                let record = record?;
+                if record.deleted {
+                    records_removed += 1;
+                } else {
+                    live.push((record.lsn, record.position));
+                }
+            }
+            live.sort_unstable();
+            for (_, position) in live {
+                records_kept += 1;
+                let mut record = reader.read_record_at(position)?;
+                rewrite(&mut record)?;
+                copy_in_batches(&mut target, &mut batch, record)?;
+            }
+        } else {
+            for record in reader {
+                let record = record?;
+                if record.deleted {
+                    records_removed += 1;
+                    continue;
+                }
+                records_kept += 1;
+                let mut record = *record;
+                rewrite(&mut record)?;
+                copy_in_batches(&mut target, &mut batch, record)?;
+            }
+        }
+
+        if !batch.is_empty() {
+            target.bulk_copy_records(&batch)?;
+        }
+        target.flush()?;
+        // the handle keeps its exclusive lock once the file is renamed, no other writer can open it in between
+        Ok((target.file, records_kept, records_removed))
+    }
+
+}
+
+/// Adds `record` to the batch, and copies the batch to `target` once it is full.
+fn copy_in_batches(target: &mut DiskWriter, batch: &mut Vec<Record>, record: Record) -> Result<(), StorageError> {
+    batch.push(record);
+    if batch.len() == COPY_BATCH_SIZE {
+        target.bulk_copy_records(batch)?;
+        batch.clear();
+    }
+    Ok(())
+}
+
+/// Makes a rename durable. Directories cannot be opened as files on every platform, so failures are ignored.
+pub(crate) fn sync_parent_folder(file_name: &str) {
+    let parent = match Path::new(file_name).parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new(".")
+    };
+    if let Ok(folder) = File::open(parent) {
+        let _ = folder.sync_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::disk_writer::tests::{add_numbered_records, new_test_file, read_contents};
+
+    #[test]
+    fn compact_should_keep_only_live_records() {
+        let file_name = new_test_file("compact_should_keep_only_live_records");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+
+        let positions = add_numbered_records(&mut writer, 0..300);
+        for position in positions.iter().step_by(2) {
+            writer.delete_record(*position).unwrap();
+        }
+
+        let report = writer.compact().unwrap();
+
+        assert_eq!(150, report.records_kept);
+        assert_eq!(150, report.records_removed);
+        assert!(report.bytes_reclaimed > 0);
+        assert_eq!(150, writer.meta.get().records_count);
+        assert!(!Path::new(&writer.compaction_file_name()).exists());
+
+        let contents: Vec<String> = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap()
+            .map(|r| String::from_utf8(r.unwrap().content).unwrap())
+            .collect();
+        assert_eq!(150, contents.len());
+        assert_eq!("Record number 1!", contents[0]);
+        assert_eq!("Record number 299!", contents[149]);
+
+        let position = writer.add_record(b"after compaction").unwrap();
+        let last = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap().last().unwrap();
+        assert_eq!(position, last.unwrap().position);
+    }
+
+    #[test]
+    fn compact_should_count_records_copied_in_several_batches() {
+        let file_name = new_test_file("compact_should_count_records_copied_in_several_batches");
+        let mut writer = DiskWriter::new(&file_name, 64 * 1024, DurabilityMode::OsBuffered).unwrap();
+
+        let contents: Vec<Vec<u8>> = (0..2600).map(|i| format!("Record number {}!", i).into_bytes()).collect();
+        writer.bulk_add_records(contents.iter().map(|c| c.as_slice()).collect()).unwrap();
+        let positions: Vec<u64> = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap()
+            .map(|r| r.unwrap().position)
+            .collect();
+        for position in &positions[..100] {
+            writer.delete_record(*position).unwrap();
+        }
+
+        let report = writer.compact().unwrap();
+
+        assert_eq!(2500, report.records_kept);
+        assert_eq!(2500, writer.meta.get().records_count);
+        drop(writer);
+        let reader = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap();
+        assert_eq!(2500, reader.meta.get().records_count);
+        assert_eq!(2500, reader.count());
+    }
+
+    #[test]
+    fn compact_should_ignore_leftover_of_interrupted_compaction() {
+        let file_name = new_test_file("compact_should_ignore_leftover_of_interrupted_compaction");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.add_record(b"kept").unwrap();
+
+        std::fs::write(writer.compaction_file_name(), b"garbage").unwrap();
+
+        let report = writer.compact().unwrap();
+
+        assert_eq!(1, report.records_kept);
+        let contents = read_contents(&file_name, DiskReaderOptions::unlocked());
+        assert_eq!(vec![b"kept".to_vec()], contents);
+    }
+
+    #[test]
+    fn writer_should_go_on_with_the_new_file_when_the_swap_of_the_index_fails() {
+        let file_name = new_test_file("writer_should_go_on_with_the_new_file_when_the_swap_of_the_index_fails");
+        let mut writer = DiskWriter::new(&file_name, 256, DurabilityMode::EveryWrite).unwrap();
+        let positions = add_numbered_records(&mut writer, 0..10);
+        for position in &positions[..5] {
+            writer.delete_record(*position).unwrap();
+        }
+
+        // no file can be renamed over a folder
+        let index_file_name = SparseIndex::file_name_of(&file_name);
+        let _ = std::fs::remove_file(&index_file_name);
+        std::fs::create_dir(&index_file_name).unwrap();
+        assert!(writer.compact().is_err());
+        std::fs::remove_dir(&index_file_name).unwrap();
+
+        writer.add_record(b"after compaction").unwrap();
+        let expected: Vec<Vec<u8>> = (5..10).map(|i| format!("Record number {}!", i).into_bytes()).chain([b"after compaction".to_vec()]).collect();
+        assert_eq!(expected, read_contents(&file_name, DiskReaderOptions::unlocked()));
+    }
+
+}
@@ -0,0 +1,96 @@
+use crate::storage::error::StorageError;
+
+/// Bits of the record flags holding the codec the content was compressed with.
+pub const RECORD_FLAGS_CODEC_MASK: u8 = 0x0F;
+
+/// Codec applied by `DiskWriter` to the content of the records it writes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Lz4
+}
+
+impl Compression {
+
+    /// Value of the codec bits in the record flags.
+    pub fn flag(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1
+        }
+    }
+
+    /// Codec a record was written with, `None` if its flags name a codec this build does not know.
+    pub fn from_flags(flags: u8) -> Option<Compression> {
+        match flags & RECORD_FLAGS_CODEC_MASK {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            _ => None
+        }
+    }
+
+    /// Compresses `content`. Returns `None` when compression does not make it smaller,
+    /// the content is then stored as is.
+    pub fn compress(self, content: &[u8]) -> Option<Vec<u8>> {
+        let compressed = match self {
+            Compression::None => return None,
+            Compression::Lz4 => lz4_flex::compress_prepend_size(content)
+        };
+
+        if compressed.len() < content.len() { Some(compressed) } else { None }
+    }
+
+    /// Restores the content of the record at `position`, stored with this codec.
+    pub fn decompress(self, position: u64, stored: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+        match self {
+            Compression::None => Ok(stored),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(&stored)
+                .map_err(|_| StorageError::Corrupted { position })
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::disk_reader::{DiskReader, DiskReaderOptions};
+    use crate::storage::disk_writer::{DiskWriter, RECORD_FLAG_METADATA, RECORD_METADATA_SIZE};
+    use crate::storage::disk_writer::tests::new_test_file;
+    use crate::storage::durability::DurabilityMode;
+
+    #[test]
+    fn compressed_records_should_be_read_back_transparently() {
+        let file_name = new_test_file("compressed_records_should_be_read_back_transparently");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.compression = Compression::Lz4;
+
+        let content = "Record number 1! ".repeat(100).into_bytes();
+        let position = writer.add_record(&content).unwrap();
+        writer.bulk_add_records(vec![&content, b"tiny"]).unwrap();
+
+        let mut reader = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap();
+        let record = reader.read_record_at(position).unwrap();
+        assert_eq!(content, record.content);
+        assert_eq!(Compression::Lz4.flag() | RECORD_FLAG_METADATA, record.flags);
+        assert!(record.content_size < content.len() as u64);
+
+        let contents: Vec<Vec<u8>> = reader.map(|r| r.unwrap().content).collect();
+        assert_eq!(vec![content.clone(), content, b"tiny".to_vec()], contents);
+    }
+
+    #[test]
+    fn incompressible_content_should_be_stored_as_is() {
+        let file_name = new_test_file("incompressible_content_should_be_stored_as_is");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.compression = Compression::Lz4;
+
+        let position = writer.add_record(b"short").unwrap();
+
+        let mut reader = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap();
+        let record = reader.read_record_at(position).unwrap();
+        assert_eq!(RECORD_FLAG_METADATA, record.flags);
+        assert_eq!(5 + RECORD_METADATA_SIZE, record.content_size);
+    }
+
+}
@@ -1,19 +1,34 @@
-﻿use std::borrow::Cow;
-use std::cell::Cell;
+﻿use std::cell::Cell;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom};
-use bytes::BytesMut;
-use crate::binary::BinaryReader;
+use std::io::{Seek, SeekFrom};
 use crate::storage::disk_writer::{Record, RecordsFileMeta};
+use crate::storage::encryption::EncryptionKey;
+use crate::storage::overflow::RECORD_FLAG_CONTINUATION;
+use crate::storage::error::StorageError;
+use crate::storage::locking::{lock_file, FileLock};
 
+#[derive(Clone, Copy)]
 pub struct DiskReaderOptions {
-    pub max_record_size: u64
+    /// Maximum size of a record as stored, or of each chunk of a record split in chunks.
+    pub max_record_size: u64,
+    /// When set, records flagged as deleted are not returned by the iterator nor by `find_record`.
+    pub skip_deleted: bool,
+    /// Key of an encrypted file, it must be the one the file was written with.
+    pub encryption_key: Option<EncryptionKey>,
+    /// Shared lock taken on open, failing right away by default while a writer holds the file.
+    pub lock: FileLock
 }
 
 impl DiskReaderOptions {
 
     pub fn create_default() -> DiskReaderOptions {
-        DiskReaderOptions { max_record_size: 80 * 1024 * 1024 }
+        DiskReaderOptions { max_record_size: 80 * 1024 * 1024, skip_deleted: false, encryption_key: None, lock: FileLock::Fail }
+    }
+
+    /// Default options without lock, for a reader running next to a writer of the same process: it cannot lock the file,
+    /// see `FileLock`.
+    pub fn unlocked() -> DiskReaderOptions {
+        DiskReaderOptions { lock: FileLock::None, ..DiskReaderOptions::create_default() }
     }
 
 }
@@ -22,122 +37,295 @@ pub struct DiskReader {
     pub file_name: String,
     pub file: File,
     pub meta: Cell<RecordsFileMeta>,
-    pub position: u64,
     pub options: DiskReaderOptions
 }
 
 impl DiskReader {
 
-    pub fn new(file_name: &str, options: DiskReaderOptions) -> DiskReader {
-        let file = OpenOptions::new().read(true).open(file_name).unwrap();
+    pub fn new(file_name: &str, options: DiskReaderOptions) -> Result<DiskReader, StorageError> {
+        let mut file = OpenOptions::new().read(true).open(file_name)?;
+        lock_file(&file, file_name, false, options.lock)?;
+        let meta = RecordsFileMeta::read_metadata(&mut file)?;
+        DiskReader::from_file(file_name, file, meta, options)
+    }
+
+    /// Opens a reader that only sees the records committed in `meta`, whatever the file holds beyond them.
+    pub fn at_snapshot(file_name: &str, meta: RecordsFileMeta, options: DiskReaderOptions) -> Result<DiskReader, StorageError> {
+        let file = OpenOptions::new().read(true).open(file_name)?;
+        lock_file(&file, file_name, false, options.lock)?;
+        DiskReader::from_file(file_name, file, meta, options)
+    }
+
+    fn from_file(file_name: &str, file: File, meta: RecordsFileMeta, mut options: DiskReaderOptions) -> Result<DiskReader, StorageError> {
+        meta.check_key(options.encryption_key.as_ref())?;
+        if meta.key_id.is_none() {
+            // the records of a file written in clear are all read without key
+            options.encryption_key = None;
+        }
 
         let mut reader = DiskReader {
             file_name: String::from(file_name),
             file,
-            meta: Cell::new(RecordsFileMeta::empty()),
-            position: RecordsFileMeta::size() as u64,
+            meta: Cell::new(meta),
             options
         };
-        reader.load_metadata();
-        reader
+        reader.rewind_to_start()?;
+        Ok(reader)
     }
 
-    pub fn load_metadata(&mut self) {
-        let m = RecordsFileMeta::read_metadata(&mut self.file);
+    pub fn load_metadata(&mut self) -> Result<(), StorageError> {
+        let m = RecordsFileMeta::read_metadata(&mut self.file)?;
         self.meta.set(m);
+        Ok(())
     }
 
-    pub fn rewind_to_start(&mut self) {
-        (&self.file).seek(SeekFrom::Start(RecordsFileMeta::size() as u64)).unwrap();
+    /// Fails once a record was written in the space of deleted records, see `RecordsFileMeta::reused_space`:
+    /// the file is no longer in commit order, as following it or reading it backwards requires.
+    pub(crate) fn check_commit_order(&self) -> Result<(), StorageError> {
+        if self.meta.get().reused_space {
+            return Err(StorageError::ReusedSpace);
+        }
+        Ok(())
     }
 
-    pub fn seek_to(&mut self, position: u64) {
-        (&self.file).seek(SeekFrom::Start(position)).unwrap();
+    pub fn rewind_to_start(&mut self) -> Result<(), StorageError> {
+        self.seek_to(RecordsFileMeta::size() as u64)
     }
 
-    pub fn read_next_record (&mut self) -> Result<Box<Record>, Cow<'static, str>> {
-        let meta = self.meta.get();
-        let mut len_buf = vec![0; 8];
-        (&self.file).read_exact(&mut len_buf).unwrap();
-        let mut len_bin = BinaryReader::from(BytesMut::from(len_buf.as_slice()));
-        let len = len_bin.read_u64().unwrap();
-
-        let mut hash_buf = vec![0; 4];
-        (&self.file).read_exact(&mut hash_buf).unwrap();
-        let mut hash_bin = BinaryReader::from(BytesMut::from(hash_buf.as_slice()));
-        let hash = hash_bin.read_u32().unwrap();
-
-        if len > self.options.max_record_size {
-            let message = format!("record length is {} bytes. max allowed id {} bytes", len, self.options.max_record_size);
-            Err(Cow::Owned(message))
-        } else {
-            let mut buf: Vec<u8> = vec![0; len as usize];
-            (&self.file).read_exact(&mut buf).unwrap();
-
-            let mut deleted_buf: Vec<u8> = vec![0; 1];
-            (&self.file).read_exact(&mut deleted_buf).unwrap();
-            let deleted = deleted_buf[0] != 0;
-
-            let checksum = crc32fast::hash(&buf);
-
-            if checksum != hash {
-                Err(Cow::Owned("corrupted record".to_owned()))
-            }
-            else {
-                let record = Record { position: meta.position, content_size: len, content: buf.to_vec(), deleted: deleted, checksum: checksum };
-                Ok(Box::new(record))
+    pub fn seek_to(&mut self, position: u64) -> Result<(), StorageError> {
+        (&self.file).seek(SeekFrom::Start(position))?;
+        Ok(())
+    }
+
+    /// Reads the record at the cursor, reassembling it if it was split in chunks.
+    pub fn read_next_record (&mut self) -> Result<Box<Record>, StorageError> {
+        let record = Record::read_from(&self.file, self.options.max_record_size)?.decode(self.options.encryption_key.as_ref())?;
+        if record.flags & RECORD_FLAG_CONTINUATION != 0 {
+            // the first chunks of this record are missing
+            return Err(StorageError::Corrupted { position: record.position });
+        }
+        Ok(Box::new(self.read_remaining_chunks(record)?))
+    }
+
+    /// Reads the record starting at `position`, as returned by `DiskWriter::add_record`.
+    /// The iteration cursor is left where it was.
+    pub fn read_record_at(&mut self, position: u64) -> Result<Record, StorageError> {
+        let cursor = self.file.stream_position()?;
+        let record = self.read_record_at_position(position);
+        self.seek_to(cursor)?;
+        record
+    }
+
+    /// Reads the records at `positions` and returns them in the same order.
+    /// The positions are visited in ascending order so that the file is read sequentially.
+    pub fn read_records_at(&mut self, positions: &[u64]) -> Result<Vec<Record>, StorageError> {
+        let cursor = self.file.stream_position()?;
+
+        let mut order: Vec<usize> = (0..positions.len()).collect();
+        order.sort_by_key(|i| positions[*i]);
+
+        let mut records: Vec<Option<Record>> = vec![None; positions.len()];
+        for i in order {
+            match self.read_record_at_position(positions[i]) {
+                Ok(record) => records[i] = Some(record),
+                Err(e) => {
+                    self.seek_to(cursor)?;
+                    return Err(e);
+                }
             }
         }
+        self.seek_to(cursor)?;
+
+        Ok(records.into_iter().flatten().collect())
     }
 
-    pub fn find_record<F> (&mut self, f: F) -> Option<Box<Record>> where F : Fn(Box<Record>, u64) -> bool {
-        self.rewind_to_start();
+    fn read_record_at_position(&mut self, position: u64) -> Result<Record, StorageError> {
+        let record = DiskReader::read_chunk(&self.file, position, self.meta.get().position, &self.options)?;
+        if record.flags & RECORD_FLAG_CONTINUATION != 0 {
+            return Err(StorageError::InvalidPosition(position));
+        }
+        self.read_remaining_chunks(record)
+    }
+
+    /// Reads and decodes the record, or chunk of record, starting at `position` and ending before `end`.
+    pub(crate) fn read_chunk(file: &File, position: u64, end: u64, options: &DiskReaderOptions) -> Result<Record, StorageError> {
+        let min_record_size = Record::deleted_flag_offset(0) + 1;
+
+        // no file holds a record there, the position was read from corrupted data
+        let Some(record_end) = position.checked_add(min_record_size) else {
+            return Err(StorageError::Corrupted { position });
+        };
+        if position < RecordsFileMeta::size() as u64 || record_end > end {
+            return Err(StorageError::InvalidPosition(position));
+        }
+
+        let remaining = end - position - min_record_size;
+        let mut reader = file;
+        reader.seek(SeekFrom::Start(position))?;
+        match Record::read_from(file, options.max_record_size.min(remaining)) {
+            // the length prefix goes past the committed records, this is not the start of a record
+            Err(StorageError::RecordTooLarge { size, .. }) if size <= options.max_record_size => Err(StorageError::InvalidPosition(position)),
+            result => result?.decode(options.encryption_key.as_ref())
+        }
+    }
+
+    pub fn find_record<F> (&mut self, f: F) -> Result<Option<Box<Record>>, StorageError> where F : Fn(Box<Record>, u64) -> bool {
+        self.rewind_to_start()?;
 
         let mut current_id = 0;
         let meta = self.meta.get();
 
-        loop {
+        while self.file.stream_position()? < meta.position {
             current_id += 1;
 
-            if self.file.stream_position().unwrap() < meta.position {
-                let res = self.read_next_record();
-                match res {
-                    Err(_) => break None,
-                    Ok(record) => {
-                        if f(record.clone(), current_id) {
-                            break Some(record);
-                        }
-                    }
-                }
-            } else {
-                break None
+            let record = self.read_next_record()?;
+            if self.options.skip_deleted && record.deleted {
+                continue;
+            }
+            if f(record.clone(), current_id) {
+                return Ok(Some(record));
             }
-
         }
+
+        Ok(None)
     }
 
 }
 
 impl Iterator for DiskReader {
 
-    type Item = Box<Record>;
+    type Item = Result<Box<Record>, StorageError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         let meta = self.meta.get();
 
         loop {
-            if self.file.stream_position().unwrap() < meta.position {
-                let res = self.read_next_record();
-                match res {
-                    Err(_) => break None,
-                    Ok(record) => {
-                        break Some(record);
+            let position = match self.file.stream_position() {
+                Ok(position) => position,
+                Err(e) => break Some(Err(StorageError::Io(e)))
+            };
+            if position >= meta.position {
+                break None;
+            }
+
+            match self.read_next_record() {
+                Err(e) => {
+                    // the following records cannot be located, stop the iteration after reporting the error
+                    let _ = self.seek_to(meta.position);
+                    break Some(Err(e));
+                },
+                Ok(record) => {
+                    if self.options.skip_deleted && record.deleted {
+                        continue;
                     }
+                    break Some(Ok(record));
                 }
-            } else {
-                break None
             }
         }
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use super::*;
+    use crate::storage::disk_writer::{DiskWriter, RECORD_METADATA_SIZE};
+    use crate::storage::disk_writer::tests::{add_numbered_records, new_test_file};
+    use crate::storage::durability::DurabilityMode;
+
+    #[test]
+    fn read_record_at_should_return_record_written_at_position() {
+        let file_name = new_test_file("read_record_at_should_return_record_written_at_position");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        let positions = add_numbered_records(&mut writer, 0..10);
+
+        let mut reader = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap();
+        let first = reader.next().unwrap().unwrap();
+        let record = reader.read_record_at(positions[7]).unwrap();
+
+        assert_eq!(b"Record number 7!".to_vec(), record.content);
+        assert_eq!(positions[7], record.position);
+        assert_eq!(first.position, positions[0]);
+        assert_eq!(b"Record number 1!".to_vec(), reader.next().unwrap().unwrap().content);
+    }
+
+    #[test]
+    fn read_record_at_invalid_position_should_fail() {
+        let file_name = new_test_file("read_record_at_invalid_position_should_fail");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        let position = writer.add_record(b"hello world").unwrap();
+
+        let mut reader = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap();
+
+        assert!(reader.read_record_at(0).is_err());
+        assert!(reader.read_record_at(position + 2).is_err());
+        assert!(reader.read_record_at(position + 1000).is_err());
+    }
+
+    #[test]
+    fn chunk_at_largest_position_should_be_corrupted() {
+        let file_name = new_test_file("chunk_at_largest_position_should_be_corrupted");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.add_record(b"hello world").unwrap();
+
+        let file = File::open(&file_name).unwrap();
+        let result = DiskReader::read_chunk(&file, u64::MAX, u64::MAX, &DiskReaderOptions::unlocked());
+        assert!(matches!(result, Err(StorageError::Corrupted { position: u64::MAX })));
+        let mut reader = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap();
+        assert!(matches!(reader.read_record_at(u64::MAX), Err(StorageError::Corrupted { position: u64::MAX })));
+    }
+
+    #[test]
+    fn read_records_at_should_keep_requested_order() {
+        let file_name = new_test_file("read_records_at_should_keep_requested_order");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        let positions = add_numbered_records(&mut writer, 0..10);
+
+        let mut reader = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap();
+        let records = reader.read_records_at(&[positions[8], positions[2], positions[5], positions[2]]).unwrap();
+        let contents: Vec<String> = records.into_iter().map(|r| String::from_utf8(r.content).unwrap()).collect();
+
+        assert_eq!(vec!["Record number 8!", "Record number 2!", "Record number 5!", "Record number 2!"], contents);
+    }
+
+    #[test]
+    fn opening_missing_file_should_fail_with_io_error() {
+        let file_name = new_test_file("opening_missing_file_should_fail_with_io_error");
+
+        let result = DiskReader::new(&file_name, DiskReaderOptions::unlocked());
+
+        assert!(matches!(result, Err(StorageError::Io(_))));
+    }
+
+    #[test]
+    fn iteration_should_report_corrupted_record_then_stop() {
+        let file_name = new_test_file("iteration_should_report_corrupted_record_then_stop");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.add_record(b"first").unwrap();
+        let second = writer.add_record(b"second").unwrap();
+        writer.add_record(b"third").unwrap();
+
+        (&writer.file).seek(SeekFrom::Start(second + 8 + 4)).unwrap();
+        (&writer.file).write_all(b"X").unwrap();
+
+        let mut reader = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap();
+
+        assert!(reader.next().unwrap().is_ok());
+        assert!(matches!(reader.next(), Some(Err(StorageError::Corrupted { position })) if position == second));
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn record_larger_than_max_record_size_should_be_rejected() {
+        let file_name = new_test_file("record_larger_than_max_record_size_should_be_rejected");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        let position = writer.add_record(&[7; 100]).unwrap();
+
+        let options = DiskReaderOptions { max_record_size: 10, ..DiskReaderOptions::unlocked() };
+        let mut reader = DiskReader::new(&file_name, options).unwrap();
+
+        assert!(matches!(reader.read_record_at(position), Err(StorageError::RecordTooLarge { size, max_size: 10, .. }) if size == 100 + RECORD_METADATA_SIZE));
+    }
+
+}
@@ -1,74 +1,369 @@
 use bytes::{BufMut, BytesMut};
 
 use crate::binary::*;
+use crate::storage::compression::Compression;
+use crate::storage::encryption::{EncryptionKey, RECORD_FLAG_ENCRYPTED};
+use crate::storage::free_space::FreeSpaceMap;
+use crate::storage::sparse_index::SparseIndex;
+use crate::storage::overflow::{DEFAULT_MAX_CHUNK_SIZE, RECORD_FLAG_CONTINUATION};
+use crate::storage::durability::{DurabilityMode, GroupCommitter};
+use crate::storage::error::StorageError;
+use crate::storage::recovery::RecoveryReport;
+use crate::storage::locking::{is_same_file, lock_file, FileLock};
 use std::cell::Cell;
 use std::fs::{File, OpenOptions};
 use std::io::{prelude::*, SeekFrom};
 use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::vec;
 
-#[derive(Clone, Copy)]
+/// Version of the files written by this build, the only one it reads.
+/// Version 1 files are converted by `upgrade::upgrade_file`.
+pub const RECORDS_FILE_VERSION: u64 = 2;
+/// Bits of the record length prefix holding the record size, the highest byte holds the record flags.
+pub const RECORD_LENGTH_MASK: u64 = 0x00FF_FFFF_FFFF_FFFF;
+/// Record flag set when the stored content starts with the LSN and the commit timestamp of the record.
+/// Only the first chunk of a record split in chunks carries them. They are stored in clear, and authenticated
+/// with the content of an encrypted record.
+pub const RECORD_FLAG_METADATA: u8 = 0x80;
+/// Size of the LSN and commit timestamp stored before the content.
+pub const RECORD_METADATA_SIZE: u64 = 8 + 8;
+/// Header flag set for an encrypted file.
+const HEADER_FLAG_ENCRYPTED: u8 = 0x01;
+/// Header flag set once a record was written in the space of deleted records, see `RecordsFileMeta::reused_space`.
+const HEADER_FLAG_REUSED_SPACE: u8 = 0x02;
+
+/// File header. It is stored twice, in two alternating slots at the start of the file:
+/// each commit goes to the slot not holding the latest metadata, so a torn write can only damage
+/// the commit in progress and the previous one is still readable.
+///
+/// Slot layout:
+///
+/// ```text
+/// | version | records_count | position | page_size | sequence | flags  | key_id  | key_check | last_lsn | crc32   |
+/// | 8 bytes | 8 bytes       | 8 bytes  | 8 bytes   | 8 bytes  | 1 byte | 4 bytes | 8 bytes   | 7 bytes  | 4 bytes |
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct RecordsFileMeta {
     pub version: u64,
     pub records_count: u64,
     pub position: u64,
-    pub page_size: u64
+    pub page_size: u64,
+    pub sequence: u64,
+    /// Id of the key the records are encrypted with, `None` for a file written in clear.
+    pub key_id: Option<u32>,
+    /// `EncryptionKey::check_value` of that key.
+    pub key_check: u64,
+    /// Highest LSN given to a record of the file, stored on 56 bits. It survives the deletion and compaction
+    /// of that record, so an LSN is never given twice.
+    pub last_lsn: u64,
+    /// Set once a record was written in the space of deleted records: the records are no longer in LSN order
+    /// in the file, so the file cannot be followed or read backwards. A compaction copies the records in LSN order
+    /// to a file without it.
+    pub reused_space: bool
 }
 
 impl RecordsFileMeta {
 
+    /// Size of the whole header, records start right after it.
     pub fn size() -> usize {
-        8 + 8 + 8 + 8
+        2 * RecordsFileMeta::slot_size()
+    }
+
+    pub fn slot_size() -> usize {
+        64
     }
 
     pub fn empty() -> RecordsFileMeta {
-        RecordsFileMeta { version: 1, records_count:0, position: RecordsFileMeta::size() as u64, page_size: 0 }
+        RecordsFileMeta::empty_with_page_size(0)
     }
 
     pub fn empty_with_page_size(page_size: u64) -> RecordsFileMeta {
-        RecordsFileMeta { version: 1, records_count:0, position: RecordsFileMeta::size() as u64, page_size: page_size }
+        RecordsFileMeta { version: RECORDS_FILE_VERSION, records_count: 0, position: RecordsFileMeta::size() as u64, page_size, sequence: 0, key_id: None, key_check: 0, last_lsn: 0, reused_space: false }
     }
 
-    pub fn read_metadata(file: &mut File) -> RecordsFileMeta {
-        file.seek(SeekFrom::Start(0)).unwrap();
-        let mut buf = vec![0; RecordsFileMeta::size()];
-        file.read(&mut buf).unwrap();
-        let bytes = BytesMut::from(buf.as_slice());
-        let mut bin = BinaryReader::from(bytes);
+    pub fn slot_position(sequence: u64) -> u64 {
+        (sequence % 2) * RecordsFileMeta::slot_size() as u64
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bin = BinaryWriter::with_capacity(RecordsFileMeta::slot_size());
+        bin.write_u64(self.version);
+        bin.write_u64(self.records_count);
+        bin.write_u64(self.position);
+        bin.write_u64(self.page_size);
+        bin.write_u64(self.sequence);
+        let mut flags = 0;
+        if self.key_id.is_some() {
+            flags |= HEADER_FLAG_ENCRYPTED;
+        }
+        if self.reused_space {
+            flags |= HEADER_FLAG_REUSED_SPACE;
+        }
+        bin.write_u8(flags);
+        bin.write_u32(self.key_id.unwrap_or(0));
+        bin.write_u64(self.key_check);
+        for b in &self.last_lsn.to_be_bytes()[1..] {
+            bin.write_u8(*b);
+        }
+
+        let mut content = bin.buffer.to_vec();
+        content.resize(RecordsFileMeta::slot_size() - 4, 0);
+        let checksum = crc32fast::hash(&content);
+        content.extend_from_slice(&checksum.to_be_bytes());
+        content
+    }
+
+    /// Decodes a slot, returns `None` if its checksum does not match.
+    pub fn from_bytes(buf: &[u8]) -> Option<RecordsFileMeta> {
+        let crc_position = RecordsFileMeta::slot_size() - 4;
+        let mut checksum: [u8; 4] = Default::default();
+        checksum.copy_from_slice(&buf[crc_position..RecordsFileMeta::slot_size()]);
+
+        if crc32fast::hash(&buf[..crc_position]) != u32::from_be_bytes(checksum) {
+            return None;
+        }
+
+        let mut bin = BinaryReader::from(&buf[..crc_position]);
+        let version = bin.read_u64().ok()?;
+        let records_count = bin.read_u64().ok()?;
+        let position = bin.read_u64().ok()?;
+        let page_size = bin.read_u64().ok()?;
+        let sequence = bin.read_u64().ok()?;
+        let flags = bin.read_u8().ok()?;
+        let key_id = bin.read_u32().ok()?;
+        let key_check = bin.read_u64().ok()?;
+        let mut last_lsn = 0;
+        for _ in 0..7 {
+            last_lsn = (last_lsn << 8) | bin.read_u8().ok()? as u64;
+        }
+
+        Some(RecordsFileMeta {
+            version,
+            records_count,
+            position,
+            page_size,
+            sequence,
+            key_id: if flags & HEADER_FLAG_ENCRYPTED != 0 { Some(key_id) } else { None },
+            key_check,
+            last_lsn,
+            reused_space: flags & HEADER_FLAG_REUSED_SPACE != 0
+        })
+    }
+
+    /// Writes the metadata into the slot of its sequence, without syncing.
+    pub fn write_slot(self, file: &File) -> Result<(), StorageError> {
+        let mut writer = file;
+        writer.seek(SeekFrom::Start(RecordsFileMeta::slot_position(self.sequence)))?;
+        writer.write_all(&self.to_bytes())?;
+        Ok(())
+    }
+
+    /// Reads both slots and returns the valid one with the highest sequence, if its version is supported.
+    /// Fails with `OutdatedVersion` for a file to upgrade, and with `UnsupportedVersion` for a version newer than this build.
+    pub fn read_metadata(file: &mut File) -> Result<RecordsFileMeta, StorageError> {
+        let meta = match RecordsFileMeta::read_latest_slot(file) {
+            // version 1 headers have a single block and no checksum
+            Err(StorageError::CorruptedHeader) if RecordsFileMeta::read_v1_header(file)?.is_some() => return Err(StorageError::OutdatedVersion(1)),
+            result => result?
+        };
+
+        if meta.version != RECORDS_FILE_VERSION {
+            return Err(StorageError::UnsupportedVersion(meta.version));
+        }
+        Ok(meta)
+    }
 
-        let version = bin.read_u64().unwrap();
-        let records_count = bin.read_u64().unwrap();
-        let position = bin.read_u64().unwrap();
-        let page_size = bin.read_u64().unwrap();
+    /// Reads both slots and returns the valid one with the highest sequence, whatever its version.
+    pub fn read_latest_slot(file: &mut File) -> Result<RecordsFileMeta, StorageError> {
+        file.seek(SeekFrom::Start(0))?;
+        let mut buf = vec![0; RecordsFileMeta::size()];
+        file.read_exact(&mut buf).map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => StorageError::CorruptedHeader,
+            _ => StorageError::Io(e)
+        })?;
+
+        let (first, second) = buf.split_at(RecordsFileMeta::slot_size());
+        let slots = [RecordsFileMeta::from_bytes(first), RecordsFileMeta::from_bytes(second)];
+
+        slots.into_iter()
+            .flatten()
+            .max_by_key(|m| m.sequence)
+            .ok_or(StorageError::CorruptedHeader)
+    }
 
-        RecordsFileMeta { version, records_count, position, page_size }
+    /// Checks that `key` is the one the records are encrypted with.
+    /// Any key is accepted for a file written in clear.
+    pub fn check_key(&self, key: Option<&EncryptionKey>) -> Result<(), StorageError> {
+        match (self.key_id, key) {
+            (None, _) => Ok(()),
+            (Some(_), None) => Err(StorageError::MissingKey),
+            (Some(id), Some(key)) if key.id == id && key.check_value() == self.key_check => Ok(()),
+            (Some(id), Some(_)) => Err(StorageError::WrongKey(id))
+        }
     }
 }
 
+/// Record layout:
+///
+/// ```text
+/// | flags  | size    | crc32   | content      | deleted |
+/// | 1 byte | 7 bytes | 4 bytes | `size` bytes | 1 byte  |
+/// ```
+///
+/// The flags tell how the content is stored: the low bits hold the `Compression` codec,
+/// `RECORD_FLAG_ENCRYPTED` is set when it is encrypted, the chunk flags of `overflow` chain
+/// the chunks of a record split in several ones, and `RECORD_FLAG_METADATA` is set when the content
+/// starts with the LSN and the commit timestamp of the record, neither compressed nor encrypted but authenticated
+/// with the content when it is encrypted.
 pub struct Record {
     pub position: u64,
+    /// Size of the content as stored in the file. For a record split in chunks, size of its first chunk.
     pub content_size: u64,
+    /// Content as stored in the file, or decompressed once read through a `DiskReader`.
     pub content: Vec<u8>,
     pub deleted: bool,
-    pub checksum: u32
+    pub checksum: u32,
+    pub flags: u8,
+    /// Log sequence number given by the writer, increasing with each record added to the file. 0 when the record has none.
+    pub lsn: u64,
+    /// When the writer committed the record, in microseconds since the Unix epoch. 0 when the record has none.
+    pub timestamp: u64
 }
 
 impl Clone for Record {
     fn clone(&self) -> Self {
-        Self { position: self.position.clone(), content_size: self.content_size.clone(), content: self.content.clone(), deleted: self.deleted.clone(), checksum: self.checksum.clone() }
+        Self { position: self.position, content_size: self.content_size, content: self.content.clone(), deleted: self.deleted, checksum: self.checksum, flags: self.flags, lsn: self.lsn, timestamp: self.timestamp }
     }
 }
 
 impl Record {
 
     pub fn size (&self) -> u64 {
-        // length prefix + checksum + content + deleted flag
+        // flags and length prefix + checksum + content + deleted flag
         8 + 4 + self.content_size + 1
     }
 
+    pub fn new(position: u64, content: &[u8]) -> Record {
+        Record::with_flags(position, content.to_vec(), 0)
+    }
+
+    /// Builds the record storing `content` compressed with `compression`, when that makes it smaller,
+    /// then encrypted with `key` if one is given, after the LSN and commit timestamp of `metadata` if any.
+    /// `chunk_flags` are set as is.
+    pub fn encode(position: u64, content: &[u8], compression: Compression, key: Option<&EncryptionKey>, chunk_flags: u8, metadata: Option<(u64, u64)>) -> Result<Record, StorageError> {
+        let (mut stored, mut flags) = match compression.compress(content) {
+            Some(compressed) => (compressed, compression.flag() | chunk_flags),
+            None => (content.to_vec(), chunk_flags)
+        };
+        let mut prefix = Vec::new();
+        if let Some((lsn, timestamp)) = metadata {
+            prefix.extend_from_slice(&lsn.to_be_bytes());
+            prefix.extend_from_slice(&timestamp.to_be_bytes());
+            flags |= RECORD_FLAG_METADATA;
+        }
+        if let Some(key) = key {
+            stored = key.encrypt(position, &prefix, &stored)?;
+            flags |= RECORD_FLAG_ENCRYPTED;
+        }
+        prefix.extend_from_slice(&stored);
+        Ok(Record::with_flags(position, prefix, flags))
+    }
+
+    fn with_flags(position: u64, stored: Vec<u8>, flags: u8) -> Record {
+        let checksum = Record::compute_checksum(flags, &stored);
+        let (lsn, timestamp) = Record::metadata_of(flags, &stored);
+        Record { position, content_size: stored.len() as u64, content: stored, deleted: false, checksum, flags, lsn, timestamp }
+    }
+
+    /// LSN and commit timestamp at the start of a stored content, zeros for a record without them.
+    pub fn metadata_of(flags: u8, stored: &[u8]) -> (u64, u64) {
+        if flags & RECORD_FLAG_METADATA == 0 || stored.len() < RECORD_METADATA_SIZE as usize {
+            return (0, 0);
+        }
+        (u64::from_be_bytes(stored[0..8].try_into().unwrap()), u64::from_be_bytes(stored[8..16].try_into().unwrap()))
+    }
+
+    /// Current time as a commit timestamp, in microseconds since the Unix epoch.
+    pub fn now_timestamp() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64)
+    }
+
+    /// Checksum stored in the record, it covers the flags, the length prefix and the content as stored.
+    /// Covering the length means a zero filled area is never mistaken for an empty record.
+    pub fn compute_checksum(flags: u8, content: &[u8]) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&Record::length_prefix(flags, content.len() as u64).to_be_bytes());
+        hasher.update(content);
+        hasher.finalize()
+    }
+
+    fn length_prefix(flags: u8, content_size: u64) -> u64 {
+        ((flags as u64) << 56) | content_size
+    }
+
+    pub fn deleted_flag_offset(content_size: u64) -> u64 {
+        8 + 4 + content_size
+    }
+
+    /// Reads the record starting at the current position of `file` and checks its checksum.
+    pub fn read_from(file: &File, max_record_size: u64) -> Result<Record, StorageError> {
+        let mut reader = file;
+        let position = reader.stream_position()?;
+
+        let mut header_buf = vec![0; 8 + 4];
+        reader.read_exact(&mut header_buf).map_err(|e| StorageError::reading_record(position, e))?;
+        let mut header_bin = BinaryReader::from(&header_buf);
+        let prefix = header_bin.read_u64().map_err(|_| StorageError::Corrupted { position })?;
+        let flags = (prefix >> 56) as u8;
+        let len = prefix & RECORD_LENGTH_MASK;
+        let hash = header_bin.read_u32().map_err(|_| StorageError::Corrupted { position })?;
+
+        if len > max_record_size {
+            return Err(StorageError::RecordTooLarge { position, size: len, max_size: max_record_size });
+        }
+
+        let mut buf: Vec<u8> = vec![0; len as usize + 1];
+        reader.read_exact(&mut buf).map_err(|e| StorageError::reading_record(position, e))?;
+
+        let deleted = buf.pop() != Some(0);
+        let checksum = Record::compute_checksum(flags, &buf);
+
+        if checksum != hash || (flags & RECORD_FLAG_METADATA != 0 && len < RECORD_METADATA_SIZE) {
+            Err(StorageError::Corrupted { position })
+        } else {
+            let (lsn, timestamp) = Record::metadata_of(flags, &buf);
+            Ok(Record { position, content_size: len, content: buf, deleted, checksum, flags, lsn, timestamp })
+        }
+    }
+
+    /// Returns the record with its content as it was given to the writer.
+    /// `key` is the key of the file, `None` for a file written in clear. Every record of an encrypted file is encrypted,
+    /// but the deleted records filling free space, which hold no content.
+    pub fn decode(mut self, key: Option<&EncryptionKey>) -> Result<Record, StorageError> {
+        // every flag bit is assigned, only a codec this build does not know cannot be decoded
+        let compression = Compression::from_flags(self.flags)
+            .ok_or(StorageError::UnsupportedRecordFlags { position: self.position, flags: self.flags })?;
+
+        let metadata: Vec<u8> = if self.flags & RECORD_FLAG_METADATA != 0 {
+            self.content.drain(..RECORD_METADATA_SIZE as usize).collect()
+        } else {
+            Vec::new()
+        };
+        if self.flags & RECORD_FLAG_ENCRYPTED != 0 {
+            let key = key.ok_or(StorageError::MissingKey)?;
+            self.content = key.decrypt(self.position, &metadata, &self.content)?;
+        } else if key.is_some() && !self.deleted {
+            return Err(StorageError::UnencryptedRecord { position: self.position });
+        }
+        self.content = compression.decompress(self.position, self.content)?;
+        Ok(self)
+    }
+
+    /// Encodes the record as stored in the file, the content must be the stored one.
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut buf = BytesMut::with_capacity(self.content_size as usize);
-        buf.put_u64(self.content_size);
+        let mut buf = BytesMut::with_capacity(self.size() as usize);
+        buf.put_u64(Record::length_prefix(self.flags, self.content_size));
         buf.put_u32(self.checksum);
         buf.put_slice(&self.content);
         buf.put_u8(self.deleted as u8);
@@ -81,163 +376,538 @@ pub struct DiskWriter {
     pub file_name: String,
     pub page_size: u64,
     pub file: File,
-    pub meta: Cell<RecordsFileMeta>
+    pub meta: Cell<RecordsFileMeta>,
+    pub durability: DurabilityMode,
+    /// Codec applied to the content of the records added from now on.
+    pub compression: Compression,
+    /// Records larger than this are split in chunks of this size, see `overflow`.
+    pub max_chunk_size: u64,
+    pub(crate) encryption_key: Option<EncryptionKey>,
+    /// Space of deleted records, reused by `add_record` when `reuse_free_space` is set.
+    pub(crate) free_space: FreeSpaceMap,
+    /// When set, `add_record` writes in the space of deleted records before appending. Unset by default:
+    /// `add_record` always appends, so the records are in LSN order in the file and committed records are never rewritten,
+    /// see `follow`, `sparse_index` and `shared`. Records deleted while it is unset are still added to the free-space map,
+    /// as they are and without rewriting anything, so that their space is reused once it is set.
+    pub reuse_free_space: bool,
+    /// Positions of some appended records, used to read the file backwards.
+    pub(crate) sparse_index: SparseIndex,
+    /// What `recover` repaired when the file was opened, `None` for a new file.
+    pub recovery: Option<RecoveryReport>,
+    group_committer: Option<GroupCommitter>
 }
 
 impl DiskWriter {
 
-    pub fn new(file_name: &str, page_size: u64) -> DiskWriter {
-        let is_new_file = !Path::new(file_name).exists();
-        let file = OpenOptions::new().create(true).read(true).write(true).open(file_name).unwrap();
+    pub fn new(file_name: &str, page_size: u64, durability: DurabilityMode) -> Result<DiskWriter, StorageError> {
+        DiskWriter::new_with_key(file_name, page_size, durability, None)
+    }
 
-        if file.metadata().unwrap().len() < page_size {
-            file.set_len(page_size).unwrap();
-        }
+    /// Opens the file with a key used to encrypt the records added from now on.
+    /// An existing encrypted file must be opened with the key it was written with, and an existing file written in clear
+    /// without key: `compact_with_key` changes both.
+    pub fn new_with_key(file_name: &str, page_size: u64, durability: DurabilityMode, key: Option<EncryptionKey>) -> Result<DiskWriter, StorageError> {
+        DiskWriter::new_with_lock(file_name, page_size, durability, key, FileLock::Fail)
+    }
+
+    /// Opens the file and locks it exclusively according to `lock`, before reading or writing anything.
+    /// `new` and `new_with_key` fail right away when the file is locked.
+    ///
+    /// The file is new if it is empty once locked: another writer may have created it while this one waited.
+    /// A file a compaction or an upgrade renamed over the opened one while waiting is opened again.
+    pub fn new_with_lock(file_name: &str, page_size: u64, durability: DurabilityMode, key: Option<EncryptionKey>, lock: FileLock) -> Result<DiskWriter, StorageError> {
+        let file = loop {
+            let file = OpenOptions::new().create(true).truncate(false).read(true).write(true).open(file_name)?;
+            lock_file(&file, file_name, true, lock)?;
+            if is_same_file(&file, file_name)? {
+                break file;
+            }
+        };
+        let is_new_file = file.metadata()?.len() == 0;
 
         let mut w = DiskWriter {
             file_name: String::from(file_name),
             page_size,
             file,
-            meta: Cell::new(RecordsFileMeta::empty_with_page_size(page_size))
+            meta: Cell::new(RecordsFileMeta::empty_with_page_size(page_size)),
+            durability,
+            compression: Compression::None,
+            max_chunk_size: DEFAULT_MAX_CHUNK_SIZE,
+            encryption_key: key,
+            free_space: FreeSpaceMap::empty(&FreeSpaceMap::file_name_of(file_name)),
+            reuse_free_space: false,
+            sparse_index: SparseIndex::empty(&SparseIndex::file_name_of(file_name)),
+            recovery: None,
+            group_committer: None
         };
         if !is_new_file {
-            w.load_metadata();
+            w.load_metadata()?;
+            w.meta.get().check_key(key.as_ref())?;
+            if key.is_some() && w.meta.get().key_id.is_none() {
+                return Err(StorageError::UnencryptedFile);
+            }
+            w.free_space = FreeSpaceMap::load(&FreeSpaceMap::file_name_of(file_name))?;
+            w.repair_pending_free_extent()?;
+            w.recovery = Some(w.recover()?);
+            w.free_space.truncate(w.meta.get().position);
+            w.sparse_index = SparseIndex::load(&SparseIndex::file_name_of(file_name))?;
+            w.sparse_index.truncate(w.meta.get().position)?;
         } else {
-            w.write_metadata_and_fsync(w.meta.get());
-            let m = RecordsFileMeta::read_metadata(&mut w.file);
+            // left by a previous file of the same name
+            for file_name in [&w.free_space.file_name, &w.sparse_index.file_name] {
+                if Path::new(file_name).exists() {
+                    std::fs::remove_file(file_name)?;
+                }
+            }
+            let meta = w.meta.get();
+            // the file is empty, it only grows
+            w.file.set_len(page_size)?;
+            w.write_metadata_and_fsync(RecordsFileMeta { key_id: key.map(|k| k.id), key_check: key.map_or(0, |k| k.check_value()), ..meta })?;
         }
-        w
+        w.start_group_committer()?;
+        Ok(w)
     }
 
-    pub fn load_metadata(&mut self) {
-        let m = RecordsFileMeta::read_metadata(&mut self.file);
-        self.meta.set(m);
+    pub(crate) fn start_group_committer(&mut self) -> Result<(), StorageError> {
+        if let DurabilityMode::GroupCommit { max_delay, max_batch } = self.durability {
+            self.group_committer = Some(GroupCommitter::start(&self.file_name, self.meta.get(), max_delay, max_batch)?);
+        }
+        Ok(())
     }
 
-    pub fn write_metadata_and_fsync(&self, meta: RecordsFileMeta) {
-        let mut bin = BinaryWriter::with_capacity(RecordsFileMeta::size());
-        bin.write_u64(meta.version);
-        bin.write_u64(meta.records_count);
-        bin.write_u64(meta.position);
-        bin.write_u64(meta.page_size);
+    /// Stops the background flusher after it synced everything.
+    /// Used before replacing the file, writes are synced by the writer itself until `start_group_committer` is called.
+    pub(crate) fn stop_group_committer(&mut self) -> Result<(), StorageError> {
+        if let Some(committer) = self.group_committer.take() {
+            committer.stop()?;
+            // the flusher committed with its own sequence numbers
+            self.load_metadata()?;
+        }
+        Ok(())
+    }
 
-        let content = bin.buffer.freeze().to_vec();
+    pub fn load_metadata(&mut self) -> Result<(), StorageError> {
+        let m = RecordsFileMeta::read_metadata(&mut self.file)?;
+        self.meta.set(m);
+        Ok(())
+    }
 
-        (&self.file).seek(SeekFrom::Start(0)).unwrap();
-        (&self.file).write_all(&content).unwrap();
-        (&self.file).sync_all().unwrap();
+    /// Writes `meta` with the next sequence number into the slot not holding the current metadata, without syncing.
+    pub fn write_metadata(&self, meta: RecordsFileMeta) -> Result<(), StorageError> {
+        let next = RecordsFileMeta { sequence: meta.sequence + 1, ..meta };
+        next.write_slot(&self.file)?;
+        self.meta.set(next);
+        Ok(())
     }
 
-    pub fn allocate_page (&self) {
-        let len = self.file.metadata().unwrap().len();
-        self.file.set_len(len + self.page_size).unwrap();
+    /// Commits `meta` with the next sequence number into the slot not holding the current metadata.
+    pub fn write_metadata_and_fsync(&self, meta: RecordsFileMeta) -> Result<(), StorageError> {
+        self.write_metadata(meta)?;
+        self.file.sync_all()?;
+        Ok(())
     }
 
-    pub fn allocate_page_if_needed (&self) {
-        let meta = self.meta.get();
+    /// Syncs the records written so far, then commits `meta` pointing to them.
+    pub(crate) fn sync_and_commit(&self, meta: RecordsFileMeta) -> Result<(), StorageError> {
+        // records must be durable before the metadata pointing to them is committed
+        self.file.sync_all()?;
+        self.write_metadata_and_fsync(meta)
+    }
 
-        if meta.position >= self.file.metadata().unwrap().len() {
-            self.allocate_page();
+    /// Makes `records_count` freshly written records durable according to the durability mode.
+    pub(crate) fn commit(&mut self, meta: RecordsFileMeta, records_count: u64) -> Result<(), StorageError> {
+        match &self.group_committer {
+            Some(committer) => {
+                self.meta.set(meta);
+                committer.enqueue(meta, records_count as usize)
+            },
+            None if self.durability == DurabilityMode::OsBuffered => {
+                self.write_metadata(meta)
+            },
+            None => self.sync_and_commit(meta)
         }
     }
 
-    pub fn allocate_page_if_position_need (&self, position: u64) {
-        let len = self.file.metadata().unwrap().len();
-        if position > len {
-            let page = position / self.page_size;
-            self.file.set_len(position).unwrap();
+    /// Syncs everything written so far, whatever the durability mode.
+    pub fn flush(&mut self) -> Result<(), StorageError> {
+        match &self.group_committer {
+            Some(committer) => committer.flush(),
+            None => self.sync_and_commit(self.meta.get())
         }
     }
 
-    fn write_record (&mut self, record: Record) {
-        self.allocate_page_if_needed();
-        let meta = self.meta.get_mut();
-
-        (&self.file).seek(SeekFrom::Start(meta.position)).unwrap();
-
-        let buf = record.to_bytes();
-        (&self.file).write_all(&buf).unwrap();
+    pub fn allocate_page (&self) -> Result<(), StorageError> {
+        let len = self.file.metadata()?.len();
+        self.file.set_len(len + self.page_size)?;
+        Ok(())
+    }
 
-        (&self.file).sync_all().unwrap();
+    pub fn allocate_page_if_needed (&self) -> Result<(), StorageError> {
+        let meta = self.meta.get();
 
-        meta.position += record.size();
-        meta.records_count += 1;
+        if meta.position >= self.file.metadata()?.len() {
+            self.allocate_page()?;
+        }
+        Ok(())
+    }
 
-        let m = *meta;
-        self.meta.set(m);
-        self.write_metadata_and_fsync(m);
+    pub fn allocate_page_if_position_need (&self, position: u64) -> Result<(), StorageError> {
+        let len = self.file.metadata()?.len();
+        if position > len {
+            self.file.set_len(position)?;
+        }
+        Ok(())
     }
 
-    pub fn add_record (&mut self, buf: &[u8]) -> u64 {
+    /// Writes the chunks of one record, or the record itself when it was not split.
+    fn write_record (&mut self, chunks: Vec<Record>) -> Result<(), StorageError> {
+        self.allocate_page_if_needed()?;
         let meta = self.meta.get_mut();
-        let l = buf.len() as u64;
-        let checksum = crc32fast::hash(buf);
-        let record = Record { position: meta.position, content_size: l, content: buf.to_vec(), deleted: false, checksum: checksum };
 
+        (&self.file).seek(SeekFrom::Start(meta.position))?;
         let record_position = meta.position;
+        meta.last_lsn = meta.last_lsn.max(chunks[0].lsn);
 
-        self.write_record(record);
+        for chunk in chunks {
+            let buf = chunk.to_bytes();
+            (&self.file).write_all(&buf)?;
+            meta.position += chunk.size();
+        }
+        meta.records_count += 1;
 
-        record_position
+        let m = *meta;
+        self.commit(m, 1)?;
+        self.sparse_index.add(record_position)
     }
 
-    fn fsync(&mut self) {
-        (&self.file).sync_all().unwrap();
-    }
+    /// Writes the record in the space of a deleted one when one is large enough, appends it otherwise.
+    pub fn add_record (&mut self, buf: &[u8]) -> Result<u64, StorageError> {
+        if self.reuse_free_space && let Some(position) = self.add_record_in_free_space(buf)? {
+            return Ok(position);
+        }
 
-    fn bulk_write_records (&mut self, records: Vec<Record>, initial_position: u64, max_position: u64) {
-        self.allocate_page_if_position_need(max_position);
+        let record_position = self.meta.get().position;
+        let chunks = self.encode_chunks(record_position, buf, self.next_lsn(), Record::now_timestamp())?;
 
-        (&self.file).seek(SeekFrom::Start(initial_position)).unwrap();
+        self.write_record(chunks)?;
 
-        for record in records {
-            let buf = record.to_bytes();
-            (&self.file).write_all(&buf).unwrap();
-        }
+        Ok(record_position)
+    }
 
-        self.fsync();
+    pub fn fsync(&mut self) -> Result<(), StorageError> {
+        self.file.sync_all()?;
+        Ok(())
     }
 
-    fn update_meta_and_fsync(&mut self, records_count: u64, position: u64) {
+    fn update_meta_and_commit(&mut self, records_count: u64, position: u64, last_lsn: u64) -> Result<(), StorageError> {
         let meta_copy;
         {
             let meta = self.meta.get_mut();
             meta.position = position;
-            meta.records_count = records_count;
+            meta.records_count += records_count;
+            meta.last_lsn = meta.last_lsn.max(last_lsn);
             meta_copy = *meta;
         }
-        self.write_metadata_and_fsync(meta_copy);
+        self.commit(meta_copy, records_count)
+    }
+
+    /// LSN of the next record added.
+    pub fn next_lsn(&self) -> u64 {
+        self.meta.get().last_lsn + 1
+    }
+
+    /// Makes the LSNs given from now on follow `last_lsn`, when it is higher than the last one of the file.
+    /// Used when records move to another file, so that their LSNs are not given again.
+    pub(crate) fn raise_last_lsn(&mut self, last_lsn: u64) -> Result<(), StorageError> {
+        if self.meta.get().last_lsn >= last_lsn {
+            return Ok(());
+        }
+        self.stop_group_committer()?;
+        self.write_metadata_and_fsync(RecordsFileMeta { last_lsn, ..self.meta.get() })?;
+        self.start_group_committer()
+    }
+
+    /// Appends the records in one write. The free space of deleted records is only reused by `add_record`.
+    /// The records get consecutive LSNs and the same commit timestamp.
+    pub fn bulk_add_records (&mut self, buffers: Vec<&[u8]>) -> Result<(), StorageError> {
+        let first_lsn = self.next_lsn();
+        let timestamp = Record::now_timestamp();
+        let records = buffers.into_iter().enumerate().map(|(i, buf)| (buf, first_lsn + i as u64, timestamp)).collect();
+        self.bulk_append(records)
+    }
+
+    /// Appends copies of `records`, keeping their LSN and commit timestamp. Used by compaction.
+    pub(crate) fn bulk_copy_records(&mut self, records: &[Record]) -> Result<(), StorageError> {
+        self.bulk_append(records.iter().map(|r| (r.content.as_slice(), r.lsn, r.timestamp)).collect())
     }
 
-    pub fn bulk_add_records (&mut self, buffers: Vec<&[u8]>) {
+    /// Appends the contents with their LSN and commit timestamp, an LSN of 0 meaning none.
+    fn bulk_append(&mut self, records: Vec<(&[u8], u64, u64)>) -> Result<(), StorageError> {
         let mut position = {
             let meta = self.meta.get_mut();
             meta.position
         };
-        (&self.file).seek(SeekFrom::Start(position)).unwrap();
+        (&self.file).seek(SeekFrom::Start(position))?;
 
-        let records_count = buffers.len() as u64;
+        let records_count = records.len() as u64;
         let mut bin_records:Vec<u8> = Vec::new();
+        let mut last_lsn = 0;
+        let mut record_positions = Vec::with_capacity(records.len());
+
+        for (buf, lsn, timestamp) in records {
+            record_positions.push(position);
+            last_lsn = last_lsn.max(lsn);
+            for chunk in self.encode_chunks(position, buf, lsn, timestamp)? {
+                position += chunk.size();
+
+                let bin_record = chunk.to_bytes();
+                bin_records.extend_from_slice(bin_record.as_slice());
+            }
+        }
+
+        self.allocate_page_if_position_need(position)?;
+
+        (&self.file).write_all(&bin_records)?;
+
+        self.update_meta_and_commit(records_count, position, last_lsn)?;
+        for record_position in record_positions {
+            self.sparse_index.add(record_position)?;
+        }
+        Ok(())
+    }
+
+    /// Marks the record written at `position` as deleted.
+    /// The record is read back and its checksum verified first, so a wrong position cannot flip a random byte.
+    /// For a record split in chunks, only its first chunk carries the deleted flag.
+    /// Its space then goes to the free-space map.
+    pub fn delete_record(&mut self, position: u64) -> Result<(), StorageError> {
+        let meta = self.meta.get();
+
+        if position < RecordsFileMeta::size() as u64 || position >= meta.position {
+            return Err(StorageError::InvalidPosition(position));
+        }
 
-        for buf in buffers {
-            let l = buf.len() as u64;
-            let checksum = crc32fast::hash(buf);
-            let record = Record { position, content_size: l, content: buf.to_vec(), deleted: false, checksum };
-            position += record.size();
+        (&self.file).seek(SeekFrom::Start(position))?;
+        let record = Record::read_from(&self.file, (meta.position - position).saturating_sub(8 + 4 + 1))
+            .map_err(|e| match e {
+                StorageError::Io(e) => StorageError::Io(e),
+                _ => StorageError::InvalidPosition(position)
+            })?;
+        if record.flags & RECORD_FLAG_CONTINUATION != 0 {
+            // chunks of a split record are deleted through the first one
+            return Err(StorageError::InvalidPosition(position));
+        }
 
-            let bin_record = record.to_bytes();
-            bin_records.extend_from_slice(bin_record.as_slice());
+        if !record.deleted {
+            (&self.file).seek(SeekFrom::Start(position + Record::deleted_flag_offset(record.content_size)))?;
+            (&self.file).write_all(&[1])?;
+            self.commit(meta, 1)?;
+            if self.reuse_free_space {
+                self.release_record(&record)?;
+            } else {
+                self.keep_released_record(&record)?;
+            }
         }
 
-        self.allocate_page_if_position_need(position);
+        Ok(())
+    }
 
-        (&self.file).write_all(&bin_records).unwrap();
+}
 
-        self.update_meta_and_fsync(records_count, position);
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::storage::disk_reader::{DiskReader, DiskReaderOptions};
+
+    pub(crate) fn new_test_file(name: &str) -> String {
+        let folder = "test_folder/storage";
+        std::fs::create_dir_all(folder).unwrap();
+        let file_name = format!("{}/{}.data", folder, name);
+        if std::fs::exists(&file_name).unwrap() {
+            std::fs::remove_file(&file_name).unwrap();
+        }
+        file_name
     }
-    
-    pub fn rewind_to_start(&mut self) {
-        (&self.file).seek(SeekFrom::Start(RecordsFileMeta::size() as u64)).unwrap();
+
+    /// Adds the records "Record number {i}!" for each `i` of `numbers`, and returns their positions.
+    pub(crate) fn add_numbered_records(writer: &mut DiskWriter, numbers: std::ops::Range<usize>) -> Vec<u64> {
+        numbers.map(|i| writer.add_record(format!("Record number {}!", i).as_bytes()).unwrap()).collect()
+    }
+
+    /// Contents of the records of the file, in file order.
+    pub(crate) fn read_contents(file_name: &str, options: DiskReaderOptions) -> Vec<Vec<u8>> {
+        DiskReader::new(file_name, options).unwrap().map(|r| r.unwrap().content).collect()
+    }
+
+    /// Pins the layout of the current version: a change to the header or to the records must bump
+    /// `RECORDS_FILE_VERSION` and teach `upgrade::upgrade_file` the previous one, then update this test.
+    #[test]
+    fn layout_should_match_records_file_version() {
+        assert_eq!(2, RECORDS_FILE_VERSION);
+
+        let record = Record::encode(RecordsFileMeta::size() as u64, b"abc", Compression::None, None, 0, Some((7, 9))).unwrap().to_bytes();
+        let expected: Vec<u8> = [
+            &[0x80, 0, 0, 0, 0, 0, 0, 19][..],
+            &[0x08, 0xd6, 0xda, 0xe2],
+            &[0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 9],
+            b"abc",
+            &[0]
+        ].concat();
+        assert_eq!(expected, record);
+
+        let meta = RecordsFileMeta { records_count: 1, position: 160, page_size: 4096, sequence: 3, key_id: Some(5), key_check: 0xABCD, last_lsn: 7, reused_space: true, ..RecordsFileMeta::empty() };
+        let mut expected = Vec::new();
+        for field in [2u64, 1, 160, 4096, 3] {
+            expected.extend_from_slice(&field.to_be_bytes());
+        }
+        expected.extend_from_slice(&[3, 0, 0, 0, 5]);
+        expected.extend_from_slice(&0xABCDu64.to_be_bytes());
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 7]);
+        expected.resize(RecordsFileMeta::slot_size() - 4, 0);
+        expected.extend_from_slice(&[0xfe, 0x79, 0x64, 0x9f]);
+        assert_eq!(expected, meta.to_bytes());
+    }
+
+    #[test]
+    fn deleted_record_should_be_skipped_by_reader() {
+        let file_name = new_test_file("deleted_record_should_be_skipped_by_reader");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+
+        writer.add_record(b"first").unwrap();
+        let second = writer.add_record(b"second").unwrap();
+        writer.add_record(b"third").unwrap();
+
+        writer.delete_record(second).unwrap();
+
+        let all: Vec<Box<Record>> = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(3, all.len());
+        assert!(all[1].deleted);
+        assert_eq!(second, all[1].position);
+
+        let options = DiskReaderOptions { skip_deleted: true, ..DiskReaderOptions::unlocked() };
+        let live = read_contents(&file_name, options);
+        assert_eq!(vec![b"first".to_vec(), b"third".to_vec()], live);
+    }
+
+    #[test]
+    fn find_record_should_ignore_deleted_records() {
+        let file_name = new_test_file("find_record_should_ignore_deleted_records");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+
+        let first = writer.add_record(b"same").unwrap();
+        writer.add_record(b"same").unwrap();
+        writer.delete_record(first).unwrap();
+
+        let options = DiskReaderOptions { skip_deleted: true, ..DiskReaderOptions::unlocked() };
+        let mut reader = DiskReader::new(&file_name, options).unwrap();
+        let found = reader.find_record(|r, _| r.content == b"same").unwrap().unwrap();
+
+        assert_ne!(first, found.position);
+    }
+
+    #[test]
+    fn metadata_should_be_committed_in_alternating_slots() {
+        let file_name = new_test_file("metadata_should_be_committed_in_alternating_slots");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+
+        writer.add_record(b"first").unwrap();
+        writer.bulk_add_records(vec![b"second", b"third"]).unwrap();
+
+        let meta = RecordsFileMeta::read_metadata(&mut writer.file).unwrap();
+        assert_eq!(3, meta.records_count);
+        assert_eq!(3, meta.sequence);
+        assert_eq!(writer.meta.get(), meta);
+        drop(writer);
+
+        let reopened = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        assert_eq!(meta, reopened.meta.get());
+    }
+
+    #[test]
+    fn torn_metadata_write_should_fall_back_to_previous_slot() {
+        let file_name = new_test_file("torn_metadata_write_should_fall_back_to_previous_slot");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+
+        writer.add_record(b"first").unwrap();
+        let previous = writer.meta.get();
+        writer.add_record(b"second").unwrap();
+        let latest = writer.meta.get();
+
+        // simulate a crash in the middle of the last commit
+        (&writer.file).seek(SeekFrom::Start(RecordsFileMeta::slot_position(latest.sequence) + 10)).unwrap();
+        (&writer.file).write_all(&[0xFF; 8]).unwrap();
+
+        let reader = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap();
+        assert_eq!(previous, reader.meta.get());
+        let contents: Vec<Vec<u8>> = reader.map(|r| r.unwrap().content).collect();
+        assert_eq!(vec![b"first".to_vec()], contents);
+    }
+
+    #[test]
+    fn read_metadata_should_fail_when_both_slots_are_corrupted() {
+        let file_name = new_test_file("read_metadata_should_fail_when_both_slots_are_corrupted");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.add_record(b"first").unwrap();
+
+        (&writer.file).seek(SeekFrom::Start(0)).unwrap();
+        (&writer.file).write_all(&vec![0xAB; RecordsFileMeta::size()]).unwrap();
+
+        assert!(RecordsFileMeta::read_metadata(&mut writer.file).is_err());
+    }
+
+    #[test]
+    fn delete_record_at_invalid_position_should_fail() {
+        let file_name = new_test_file("delete_record_at_invalid_position_should_fail");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+
+        let position = writer.add_record(b"hello world").unwrap();
+
+        assert!(writer.delete_record(position + 3).is_err());
+        assert!(writer.delete_record(position + 1000).is_err());
+    }
+
+    #[test]
+    fn records_should_get_increasing_lsn_and_commit_timestamp() {
+        let file_name = new_test_file("records_should_get_increasing_lsn_and_commit_timestamp");
+        let before = Record::now_timestamp();
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.reuse_free_space = true;
+        let first = writer.add_record(b"first").unwrap();
+        writer.bulk_add_records(vec![b"second", b"third"]).unwrap();
+        writer.delete_record(first).unwrap();
+        drop(writer);
+
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+
+        writer.reuse_free_space = true;
+        assert_eq!(3, writer.meta.get().last_lsn);
+        // reuses the space of the first record
+        assert_eq!(first, writer.add_record(b"forth").unwrap());
+
+        let options = DiskReaderOptions { skip_deleted: true, ..DiskReaderOptions::unlocked() };
+        let records: Vec<Box<Record>> = DiskReader::new(&file_name, options).unwrap().map(|r| r.unwrap()).collect();
+        let lsns: Vec<(Vec<u8>, u64)> = records.iter().map(|r| (r.content.clone(), r.lsn)).collect();
+        assert_eq!(vec![(b"forth".to_vec(), 4), (b"second".to_vec(), 2), (b"third".to_vec(), 3)], lsns);
+        assert!(records.iter().all(|r| r.timestamp >= before && r.timestamp <= Record::now_timestamp()));
+        assert_eq!(records[1].timestamp, records[2].timestamp);
+    }
+
+    #[test]
+    fn compaction_should_keep_lsn_and_never_give_it_again() {
+        let file_name = new_test_file("compaction_should_keep_lsn_and_never_give_it_again");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.add_record(b"kept").unwrap();
+        let removed = writer.add_record(b"removed").unwrap();
+        writer.delete_record(removed).unwrap();
+
+        let kept = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap().next().unwrap().unwrap();
+        writer.compact().unwrap();
+        writer.add_record(b"after").unwrap();
+
+        let records: Vec<Box<Record>> = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!((1, kept.timestamp), (records[0].lsn, records[0].timestamp));
+        assert_eq!(3, records[1].lsn);
+        assert_eq!(3, writer.meta.get().last_lsn);
     }
 
 }
@@ -0,0 +1,251 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Seek, SeekFrom, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use crate::storage::disk_writer::RecordsFileMeta;
+use crate::storage::error::StorageError;
+
+/// When the writes of a `DiskWriter` are synced to the disk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DurabilityMode {
+    /// Records and metadata are synced before `add_record` returns.
+    EveryWrite,
+    /// A background flusher syncs pending writes, sharing one fsync between all the writes made in the meantime.
+    /// A flush happens at most `max_delay` after the first pending write, or as soon as `max_batch` records are pending.
+    /// Writers block while `max_batch` records are waiting for a flush, which bounds what a crash can lose.
+    GroupCommit { max_delay: Duration, max_batch: usize },
+    /// Nothing is synced until `DiskWriter::flush` is called, the OS decides when data reaches the disk.
+    OsBuffered
+}
+
+struct GroupCommitState {
+    /// Latest metadata handed by the writer, not yet committed.
+    pending: Option<RecordsFileMeta>,
+    /// Records written and not yet synced, including those of a flush in progress.
+    pending_count: usize,
+    first_pending_at: Option<Instant>,
+    flush_requested: bool,
+    shutdown: bool,
+    /// Set when a flush failed. The flusher stops, since what reached the disk is unknown after a failed sync.
+    failure: Option<io::Error>
+}
+
+struct GroupCommitShared {
+    state: Mutex<GroupCommitState>,
+    wake_flusher: Condvar,
+    flushed: Condvar,
+    max_delay: Duration,
+    max_batch: usize
+}
+
+/// Background flusher used by `DurabilityMode::GroupCommit`.
+/// It owns its own handle on the data file, so its metadata writes never move the writer's cursor.
+pub struct GroupCommitter {
+    shared: Arc<GroupCommitShared>,
+    flusher: Option<JoinHandle<()>>
+}
+
+impl GroupCommitter {
+
+    pub fn start(file_name: &str, committed: RecordsFileMeta, max_delay: Duration, max_batch: usize) -> Result<GroupCommitter, StorageError> {
+        let file = OpenOptions::new().write(true).open(file_name)?;
+
+        let shared = Arc::new(GroupCommitShared {
+            state: Mutex::new(GroupCommitState {
+                pending: None,
+                pending_count: 0,
+                first_pending_at: None,
+                flush_requested: false,
+                shutdown: false,
+                failure: None
+            }),
+            wake_flusher: Condvar::new(),
+            flushed: Condvar::new(),
+            max_delay,
+            max_batch: max_batch.max(1)
+        });
+
+        let flusher_shared = shared.clone();
+        let flusher = std::thread::spawn(move || GroupCommitter::run_flusher(flusher_shared, file, committed.sequence));
+
+        Ok(GroupCommitter { shared, flusher: Some(flusher) })
+    }
+
+    fn failure_of(state: &GroupCommitState) -> Result<(), StorageError> {
+        match &state.failure {
+            Some(e) => Err(StorageError::Io(io::Error::new(e.kind(), e.to_string()))),
+            None => Ok(())
+        }
+    }
+
+    /// Hands the metadata covering `records_count` new records to the flusher.
+    /// Blocks while the number of unsynced records is at `max_batch`.
+    pub fn enqueue(&self, meta: RecordsFileMeta, records_count: usize) -> Result<(), StorageError> {
+        let mut state = self.shared.state.lock().unwrap();
+        GroupCommitter::failure_of(&state)?;
+
+        state.pending = Some(meta);
+        state.pending_count += records_count;
+        if state.first_pending_at.is_none() {
+            state.first_pending_at = Some(Instant::now());
+        }
+        self.shared.wake_flusher.notify_one();
+
+        while state.pending_count >= self.shared.max_batch && state.failure.is_none() {
+            state = self.shared.flushed.wait(state).unwrap();
+        }
+        GroupCommitter::failure_of(&state)
+    }
+
+    /// Forces a flush of everything enqueued so far and waits for it.
+    pub fn flush(&self) -> Result<(), StorageError> {
+        let mut state = self.shared.state.lock().unwrap();
+        state.flush_requested = true;
+        self.shared.wake_flusher.notify_one();
+
+        while (state.pending.is_some() || state.pending_count > 0) && state.failure.is_none() {
+            state = self.shared.flushed.wait(state).unwrap();
+        }
+        state.flush_requested = false;
+        GroupCommitter::failure_of(&state)
+    }
+
+    /// Flushes what is pending, stops the flusher and reports the failure of any flush.
+    pub fn stop(mut self) -> Result<(), StorageError> {
+        self.shutdown();
+        let state = self.shared.state.lock().unwrap();
+        GroupCommitter::failure_of(&state)
+    }
+
+    fn shutdown(&mut self) {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.shutdown = true;
+            self.shared.wake_flusher.notify_one();
+        }
+        if let Some(flusher) = self.flusher.take() {
+            let _ = flusher.join();
+        }
+    }
+
+    fn run_flusher(shared: Arc<GroupCommitShared>, file: File, mut sequence: u64) {
+        loop {
+            let (meta, taken) = {
+                let mut state = shared.state.lock().unwrap();
+                loop {
+                    match state.first_pending_at {
+                        None if state.shutdown => return,
+                        None => {
+                            state = shared.wake_flusher.wait(state).unwrap();
+                        },
+                        Some(first) => {
+                            let elapsed = first.elapsed();
+                            if state.shutdown || state.flush_requested || state.pending_count >= shared.max_batch || elapsed >= shared.max_delay {
+                                break;
+                            }
+                            state = shared.wake_flusher.wait_timeout(state, shared.max_delay - elapsed).unwrap().0;
+                        }
+                    }
+                }
+                state.first_pending_at = None;
+                (state.pending.take().unwrap(), state.pending_count)
+            };
+
+            sequence += 1;
+            let result = GroupCommitter::commit(&file, RecordsFileMeta { sequence, ..meta });
+
+            let mut state = shared.state.lock().unwrap();
+            state.pending_count -= taken;
+            shared.flushed.notify_all();
+
+            if let Err(e) = result {
+                state.failure = Some(e);
+                return;
+            }
+        }
+    }
+
+    fn commit(file: &File, meta: RecordsFileMeta) -> Result<(), io::Error> {
+        // same order as `DiskWriter::sync_and_commit`
+        file.sync_data()?;
+        let mut writer = file;
+        writer.seek(SeekFrom::Start(RecordsFileMeta::slot_position(meta.sequence)))?;
+        writer.write_all(&meta.to_bytes())?;
+        file.sync_data()
+    }
+
+}
+
+impl Drop for GroupCommitter {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::disk_reader::{DiskReader, DiskReaderOptions};
+    use crate::storage::disk_writer::DiskWriter;
+    use crate::storage::disk_writer::tests::{add_numbered_records, new_test_file};
+
+    fn committed_records_count(file_name: &str) -> u64 {
+        DiskReader::new(file_name, DiskReaderOptions::unlocked()).unwrap().meta.get().records_count
+    }
+
+    #[test]
+    fn group_commit_should_persist_records_of_concurrent_writers() {
+        let file_name = new_test_file("group_commit_should_persist_records_of_concurrent_writers");
+        let durability = DurabilityMode::GroupCommit { max_delay: Duration::from_millis(5), max_batch: 64 };
+        let writer = Arc::new(Mutex::new(DiskWriter::new(&file_name, 2048, durability).unwrap()));
+
+        let threads: Vec<JoinHandle<()>> = (0..4).map(|t| {
+            let writer = writer.clone();
+            std::thread::spawn(move || {
+                for i in 0..250 {
+                    writer.lock().unwrap().add_record(format!("thread {} record {}", t, i).as_bytes()).unwrap();
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        drop(writer);
+
+        assert_eq!(1000, committed_records_count(&file_name));
+        assert_eq!(1000, DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap().count());
+    }
+
+    #[test]
+    fn group_commit_should_flush_after_max_delay() {
+        let file_name = new_test_file("group_commit_should_flush_after_max_delay");
+        let durability = DurabilityMode::GroupCommit { max_delay: Duration::from_millis(20), max_batch: 1000 };
+        let mut writer = DiskWriter::new(&file_name, 2048, durability).unwrap();
+
+        writer.add_record(b"first").unwrap();
+        writer.add_record(b"second").unwrap();
+
+        let started = Instant::now();
+        while committed_records_count(&file_name) < 2 {
+            assert!(started.elapsed() < Duration::from_secs(5), "records were never flushed");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn os_buffered_writes_should_be_committed_by_flush() {
+        let file_name = new_test_file("os_buffered_writes_should_be_committed_by_flush");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::OsBuffered).unwrap();
+
+        add_numbered_records(&mut writer, 0..10);
+        writer.bulk_add_records(vec![b"bulk 1", b"bulk 2"]).unwrap();
+        writer.flush().unwrap();
+
+        let meta = RecordsFileMeta::read_metadata(&mut writer.file).unwrap();
+        assert_eq!(12, meta.records_count);
+        assert_eq!(writer.meta.get(), meta);
+    }
+
+}
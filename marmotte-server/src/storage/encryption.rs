@@ -0,0 +1,206 @@
+use std::fmt;
+use std::io;
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce};
+use chacha20poly1305::aead::{Aead, Payload};
+use crate::storage::error::StorageError;
+
+/// Record flag set when the content is encrypted. Compression, if any, is applied before encryption.
+pub const RECORD_FLAG_ENCRYPTED: u8 = 0x10;
+
+/// Encrypted contents start with their nonce, picked at random for every record.
+const NONCE_SIZE: usize = 24;
+
+/// Associated data of the value stored in the file header to recognize a key.
+const KEY_CHECK_DATA: &[u8] = b"marmotte key check";
+
+/// Key used to encrypt record contents with XChaCha20-Poly1305.
+/// The `id` is stored in the file header, so the key a file was written with can be found when keys are rotated.
+#[derive(Clone, Copy)]
+pub struct EncryptionKey {
+    pub id: u32,
+    key: [u8; 32]
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EncryptionKey {{ id: {} }}", self.id)
+    }
+}
+
+impl EncryptionKey {
+
+    pub fn new(id: u32, key: [u8; 32]) -> EncryptionKey {
+        EncryptionKey { id, key }
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.key.into())
+    }
+
+    /// Value stored in the file header to tell whether a key is the one the file was written with, without revealing it.
+    /// It is the authentication tag of an empty message under the all zero nonce, never drawn for a record.
+    pub fn check_value(&self) -> u64 {
+        let tag = self.cipher()
+            .encrypt(&XNonce::default(), Payload { msg: &[], aad: KEY_CHECK_DATA })
+            .expect("encrypting an empty message cannot fail");
+        let mut check: [u8; 8] = Default::default();
+        check.copy_from_slice(&tag[..8]);
+        u64::from_be_bytes(check)
+    }
+
+    /// Encrypts the content of the record written at `position`, stored after `metadata`. The position and the metadata
+    /// are authenticated, so a record copied elsewhere in the file or given another LSN or timestamp is rejected.
+    pub fn encrypt(&self, position: u64, metadata: &[u8], content: &[u8]) -> Result<Vec<u8>, StorageError> {
+        let mut nonce = XNonce::default();
+        getrandom::getrandom(&mut nonce).map_err(|e| StorageError::Io(io::Error::other(e.to_string())))?;
+
+        let encrypted = self.cipher()
+            .encrypt(&nonce, Payload { msg: content, aad: &EncryptionKey::associated_data(position, metadata) })
+            .map_err(|_| StorageError::Io(io::Error::other("record encryption failed")))?;
+
+        let mut stored = Vec::with_capacity(NONCE_SIZE + encrypted.len());
+        stored.extend_from_slice(&nonce);
+        stored.extend_from_slice(&encrypted);
+        Ok(stored)
+    }
+
+    /// Decrypts the content of the record at `position`, stored after `metadata`. The header check already rejected
+    /// a wrong key, so a failure here means the record was altered.
+    pub fn decrypt(&self, position: u64, metadata: &[u8], stored: &[u8]) -> Result<Vec<u8>, StorageError> {
+        if stored.len() < NONCE_SIZE {
+            return Err(StorageError::Corrupted { position });
+        }
+        let (nonce, encrypted) = stored.split_at(NONCE_SIZE);
+
+        self.cipher()
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: encrypted, aad: &EncryptionKey::associated_data(position, metadata) })
+            .map_err(|_| StorageError::Corrupted { position })
+    }
+
+    fn associated_data(position: u64, metadata: &[u8]) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(8 + metadata.len());
+        aad.extend_from_slice(&position.to_be_bytes());
+        aad.extend_from_slice(metadata);
+        aad
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::compression::Compression;
+    use crate::storage::disk_reader::{DiskReader, DiskReaderOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use crate::storage::disk_writer::{DiskWriter, Record, RECORD_METADATA_SIZE};
+    use crate::storage::mmap_reader::MmapReader;
+    use crate::storage::disk_writer::tests::{new_test_file, read_contents};
+    use crate::storage::durability::DurabilityMode;
+
+    fn options_with_key(key: Option<EncryptionKey>) -> DiskReaderOptions {
+        DiskReaderOptions { encryption_key: key, ..DiskReaderOptions::unlocked() }
+    }
+
+    #[test]
+    fn encrypted_records_should_be_read_back_with_the_key() {
+        let file_name = new_test_file("encrypted_records_should_be_read_back_with_the_key");
+        let key = EncryptionKey::new(7, [42; 32]);
+        let mut writer = DiskWriter::new_with_key(&file_name, 2048, DurabilityMode::EveryWrite, Some(key)).unwrap();
+        writer.compression = Compression::Lz4;
+
+        writer.add_record(b"john.doe@example.com").unwrap();
+        writer.bulk_add_records(vec![b"jane.doe@example.com", "x".repeat(500).as_bytes()]).unwrap();
+
+        let raw = std::fs::read(&file_name).unwrap();
+        assert!(!raw.windows(8).any(|w| w == b"john.doe"));
+        assert_eq!(Some(7), writer.meta.get().key_id);
+
+        let contents = read_contents(&file_name, options_with_key(Some(key)));
+        assert_eq!(vec![b"john.doe@example.com".to_vec(), b"jane.doe@example.com".to_vec(), "x".repeat(500).into_bytes()], contents);
+    }
+
+    #[test]
+    fn opening_encrypted_file_with_wrong_key_should_fail() {
+        let file_name = new_test_file("opening_encrypted_file_with_wrong_key_should_fail");
+        let mut writer = DiskWriter::new_with_key(&file_name, 2048, DurabilityMode::EveryWrite, Some(EncryptionKey::new(1, [1; 32]))).unwrap();
+        writer.add_record(b"secret").unwrap();
+        drop(writer);
+
+        let same_id = DiskReader::new(&file_name, options_with_key(Some(EncryptionKey::new(1, [2; 32]))));
+        let other_id = DiskWriter::new_with_key(&file_name, 2048, DurabilityMode::EveryWrite, Some(EncryptionKey::new(2, [1; 32])));
+        let no_key = DiskReader::new(&file_name, options_with_key(None));
+
+        assert!(matches!(same_id, Err(StorageError::WrongKey(1))));
+        assert!(matches!(other_id, Err(StorageError::WrongKey(1))));
+        assert!(matches!(no_key, Err(StorageError::MissingKey)));
+    }
+
+    #[test]
+    fn compact_with_key_should_rewrite_file_with_new_key() {
+        let file_name = new_test_file("compact_with_key_should_rewrite_file_with_new_key");
+        let old_key = EncryptionKey::new(1, [1; 32]);
+        let new_key = EncryptionKey::new(2, [2; 32]);
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.add_record(b"written in clear").unwrap();
+        drop(writer);
+
+        // a file written in clear is only encrypted by a compaction
+        assert!(matches!(DiskWriter::new_with_key(&file_name, 2048, DurabilityMode::EveryWrite, Some(old_key)), Err(StorageError::UnencryptedFile)));
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.compact_with_key(Some(old_key)).unwrap();
+        writer.add_record(b"written with key 1").unwrap();
+        writer.compact_with_key(Some(new_key)).unwrap();
+        writer.add_record(b"written with key 2").unwrap();
+
+        assert_eq!(Some(2), writer.meta.get().key_id);
+        assert!(matches!(DiskReader::new(&file_name, options_with_key(Some(old_key))), Err(StorageError::WrongKey(2))));
+        let contents = read_contents(&file_name, options_with_key(Some(new_key)));
+        assert_eq!(vec![b"written in clear".to_vec(), b"written with key 1".to_vec(), b"written with key 2".to_vec()], contents);
+    }
+
+    /// Rewrites the record at `position` as stored, with its checksum updated.
+    fn tamper(file_name: &str, position: u64, change: impl FnOnce(&mut Record)) {
+        let file = std::fs::OpenOptions::new().read(true).write(true).open(file_name).unwrap();
+        (&file).seek(SeekFrom::Start(position)).unwrap();
+        let mut record = Record::read_from(&file, 1024).unwrap();
+        change(&mut record);
+        record.checksum = Record::compute_checksum(record.flags, &record.content);
+        record.content_size = record.content.len() as u64;
+        (&file).seek(SeekFrom::Start(position)).unwrap();
+        (&file).write_all(&record.to_bytes()).unwrap();
+    }
+
+    #[test]
+    fn altered_metadata_of_encrypted_record_should_be_rejected() {
+        let file_name = new_test_file("altered_metadata_of_encrypted_record_should_be_rejected");
+        let key = EncryptionKey::new(7, [42; 32]);
+        let mut writer = DiskWriter::new_with_key(&file_name, 2048, DurabilityMode::EveryWrite, Some(key)).unwrap();
+        let position = writer.add_record(b"john.doe@example.com").unwrap();
+        drop(writer);
+
+        // another LSN, the content is left as is
+        tamper(&file_name, position, |record| record.content[7] = 42);
+        let mut reader = DiskReader::new(&file_name, options_with_key(Some(key))).unwrap();
+        assert!(matches!(reader.read_record_at(position), Err(StorageError::Corrupted { position: p }) if p == position));
+    }
+
+    #[test]
+    fn unencrypted_record_in_encrypted_file_should_be_rejected() {
+        let file_name = new_test_file("unencrypted_record_in_encrypted_file_should_be_rejected");
+        let key = EncryptionKey::new(7, [42; 32]);
+        let mut writer = DiskWriter::new_with_key(&file_name, 2048, DurabilityMode::EveryWrite, Some(key)).unwrap();
+        let position = writer.add_record(b"john.doe@example.com").unwrap();
+        drop(writer);
+
+        tamper(&file_name, position, |record| {
+            record.flags &= !RECORD_FLAG_ENCRYPTED;
+            record.content.truncate(RECORD_METADATA_SIZE as usize);
+            record.content.extend_from_slice(b"forged");
+        });
+        let mut reader = DiskReader::new(&file_name, options_with_key(Some(key))).unwrap();
+        assert!(matches!(reader.read_record_at(position), Err(StorageError::UnencryptedRecord { position: p }) if p == position));
+        let reader = MmapReader::new(&file_name, options_with_key(Some(key))).unwrap();
+        assert!(matches!(reader.read_record_at(position), Err(StorageError::UnencryptedRecord { position: p }) if p == position));
+    }
+
+}
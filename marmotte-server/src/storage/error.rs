@@ -0,0 +1,90 @@
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// A file operation failed.
+    Io(io::Error),
+    /// The record at `position` has an invalid length or checksum, or ends past the end of the file.
+    Corrupted { position: u64 },
+    /// None of the metadata slots of the file header is valid.
+    CorruptedHeader,
+    /// The record at `position` is larger than the maximum allowed by the reader.
+    RecordTooLarge { position: u64, size: u64, max_size: u64 },
+    /// The file was written in a format version this build does not know, by a newer build or for another kind of file.
+    UnsupportedVersion(u64),
+    /// The file was written in a format version too old to be read, `upgrade::upgrade_file` converts it.
+    OutdatedVersion(u64),
+    /// The record at `position` is stored with flags this build cannot handle.
+    UnsupportedRecordFlags { position: u64, flags: u8 },
+    /// The file is encrypted and no key was supplied.
+    MissingKey,
+    /// The file is encrypted with the key of this id, the key supplied is not that one.
+    WrongKey(u32),
+    /// The record at `position` is stored in clear in an encrypted file.
+    UnencryptedRecord { position: u64 },
+    /// A key was supplied to write in a file written in clear, `DiskWriter::compact_with_key` encrypts it.
+    UnencryptedFile,
+    /// No record starts at this position.
+    InvalidPosition(u64),
+    /// The segment does not exist or cannot be used for this operation.
+    InvalidSegment(u32),
+    /// Records were written in the space of deleted records, they are not in LSN order in the file.
+    /// It cannot be followed or read backwards until it is compacted, see `DiskWriter::reuse_free_space`.
+    ReusedSpace,
+    /// The file is locked by a handle opened in a conflicting mode, see `FileLock`.
+    Locked(String),
+    /// The document is not valid JSON, or cannot be serialized.
+    InvalidDocument(String)
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(e) => write!(f, "i/o error: {}", e),
+            StorageError::Corrupted { position } => write!(f, "corrupted record at position {}", position),
+            StorageError::CorruptedHeader => write!(f, "corrupted file header: no valid metadata slot"),
+            StorageError::RecordTooLarge { position, size, max_size } =>
+                write!(f, "record at position {} is {} bytes, max allowed is {} bytes", position, size, max_size),
+            StorageError::UnsupportedVersion(version) => write!(f, "unsupported file format version {}: written by a newer build, or not a records file", version),
+            StorageError::OutdatedVersion(version) => write!(f, "file format version {} is no longer supported, the file must be upgraded first", version),
+            StorageError::UnsupportedRecordFlags { position, flags } => write!(f, "record at position {} has unsupported flags {:#04x}", position, flags),
+            StorageError::MissingKey => write!(f, "the file is encrypted and no key was supplied"),
+            StorageError::WrongKey(key_id) => write!(f, "wrong key: the file is encrypted with key {}", key_id),
+            StorageError::UnencryptedRecord { position } => write!(f, "record at position {} is not encrypted in an encrypted file", position),
+            StorageError::UnencryptedFile => write!(f, "the file is written in clear, it must be compacted with the key to be encrypted"),
+            StorageError::InvalidPosition(position) => write!(f, "no record at position {}", position),
+            StorageError::InvalidSegment(segment) => write!(f, "invalid segment {}", segment),
+            StorageError::ReusedSpace => write!(f, "records were written in the space of deleted records, the file is no longer in commit order"),
+            StorageError::Locked(file_name) => write!(f, "file {} is locked: it is already open for writing, or for reading by a writer", file_name),
+            StorageError::InvalidDocument(reason) => write!(f, "invalid document: {}", reason)
+        }
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StorageError::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+impl StorageError {
+
+    /// Maps a short read of the record at `position` to `Corrupted`, other i/o errors are kept as is.
+    pub fn reading_record(position: u64, e: io::Error) -> StorageError {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => StorageError::Corrupted { position },
+            _ => StorageError::Io(e)
+        }
+    }
+
+}
@@ -0,0 +1,289 @@
+use std::io::Seek;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::storage::disk_reader::DiskReader;
+use crate::storage::disk_writer::{Record, RecordsFileMeta};
+use crate::storage::error::StorageError;
+use crate::storage::locking::is_same_file;
+use crate::storage::overflow::RECORD_FLAG_CONTINUATION;
+
+impl DiskReader {
+
+    /// Turns the reader into a follower yielding the records after its cursor, then the records committed later on.
+    /// The metadata is polled every `poll_interval` while there is nothing new.
+    pub fn follow(mut self, poll_interval: Duration) -> Result<Follower, StorageError> {
+        self.check_commit_order()?;
+        let position = self.file.stream_position()?;
+        let last_lsn = self.lsn_before(position)?;
+        Ok(Follower { reader: self, position, last_lsn, poll_interval })
+    }
+
+    /// Follows the file from `position`, a `Follower::position` saved by a previous consumer.
+    /// It must be the start of a record or the end of the committed records.
+    pub fn follow_from(mut self, position: u64, poll_interval: Duration) -> Result<Follower, StorageError> {
+        self.load_metadata()?;
+        self.check_commit_order()?;
+        let end = self.meta.get().position;
+
+        if position > end {
+            return Err(StorageError::InvalidPosition(position));
+        }
+        if position != RecordsFileMeta::size() as u64 && position != end {
+            let record = DiskReader::read_chunk(&self.file, position, end, &self.options)?;
+            if record.flags & RECORD_FLAG_CONTINUATION != 0 {
+                return Err(StorageError::InvalidPosition(position));
+            }
+        }
+
+        let last_lsn = self.lsn_before(position)?;
+        Ok(Follower { reader: self, position, last_lsn, poll_interval })
+    }
+
+    /// Returns an LSN at least as high as the ones of the records before `position`, the start of a record
+    /// or the end of the committed records, and below the LSNs of the records from there on.
+    fn lsn_before(&self, position: u64) -> Result<u64, StorageError> {
+        let meta = self.meta.get();
+        if position >= meta.position {
+            return Ok(meta.last_lsn);
+        }
+        let record = DiskReader::read_chunk(&self.file, position, meta.position, &self.options)?;
+        Ok(record.lsn.saturating_sub(1))
+    }
+
+}
+
+/// Reader of the records as they are committed, returned by `DiskReader::follow`.
+///
+/// The iterator blocks until the next record is committed and never ends. `position` is where the next record starts,
+/// a consumer saving it along with what it did with the previous records resumes exactly there with `follow_from`.
+///
+/// A compaction replaces the file: the follower reads the records left in the file it opened, then follows the new file
+/// from the first record it did not yield yet, found by its LSN, which a compaction keeps. The positions saved before
+/// do not match the new file. The writer must not reuse the space of deleted records, the follower fails with `ReusedSpace` once it did.
+pub struct Follower {
+    reader: DiskReader,
+    position: u64,
+    /// LSN of the last record read, the records of the file replacing the followed one up to it are skipped.
+    last_lsn: u64,
+    poll_interval: Duration
+}
+
+impl Follower {
+
+    /// Position of the next record, to resume from with `DiskReader::follow_from`.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Returns the next committed record, or `None` if there is none yet. Never blocks.
+    /// On error the position is not moved, the next call fails the same way.
+    pub fn try_next(&mut self) -> Result<Option<Box<Record>>, StorageError> {
+        loop {
+            if self.position >= self.reader.meta.get().position {
+                self.reader.load_metadata()?;
+                self.reader.check_commit_order()?;
+                if self.position >= self.reader.meta.get().position {
+                    // a replaced file no longer changes, all of its records were read
+                    if !is_same_file(&self.reader.file, &self.reader.file_name)? {
+                        self.follow_new_file()?;
+                        continue;
+                    }
+                    return Ok(None);
+                }
+            }
+
+            self.reader.seek_to(self.position)?;
+            let record = self.reader.read_next_record()?;
+            self.position = self.reader.file.stream_position()?;
+            self.last_lsn = record.lsn;
+
+            if self.reader.options.skip_deleted && record.deleted {
+                continue;
+            }
+            return Ok(Some(record));
+        }
+    }
+
+    /// Opens the file a compaction renamed over the followed one, and moves to its first record with an LSN above `last_lsn`.
+    fn follow_new_file(&mut self) -> Result<(), StorageError> {
+        let mut reader = DiskReader::new(&self.reader.file_name, self.reader.options)?;
+        reader.check_commit_order()?;
+
+        let end = reader.meta.get().position;
+        let mut position = RecordsFileMeta::size() as u64;
+        reader.seek_to(position)?;
+        while position < end && reader.read_next_record()?.lsn <= self.last_lsn {
+            position = reader.file.stream_position()?;
+        }
+
+        self.reader = reader;
+        self.position = position;
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for the next record, returns `None` if none was committed in the meantime.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Option<Box<Record>>, StorageError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(record) = self.try_next()? {
+                return Ok(Some(record));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(self.poll_interval.min(deadline - now));
+        }
+    }
+
+}
+
+impl Iterator for Follower {
+
+    type Item = Result<Box<Record>, StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.try_next() {
+                Ok(Some(record)) => return Some(Ok(record)),
+                Ok(None) => thread::sleep(self.poll_interval),
+                Err(e) => return Some(Err(e))
+            }
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::disk_reader::DiskReaderOptions;
+    use crate::storage::disk_writer::DiskWriter;
+    use crate::storage::disk_writer::tests::{add_numbered_records, new_test_file};
+    use crate::storage::durability::DurabilityMode;
+
+    const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+    #[test]
+    fn follower_should_yield_records_as_they_are_committed() {
+        let file_name = new_test_file("follower_should_yield_records_as_they_are_committed");
+        let mut writer = DiskWriter::new(&file_name, 256, DurabilityMode::EveryWrite).unwrap();
+        writer.add_record(b"before").unwrap();
+
+        let mut reader = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap();
+        assert_eq!(b"before".to_vec(), reader.next().unwrap().unwrap().content);
+        let mut follower = reader.follow(POLL_INTERVAL).unwrap();
+        assert!(follower.try_next().unwrap().is_none());
+
+        let appender = thread::spawn(move || {
+            for i in 0..20 {
+                writer.add_record(format!("Record number {}!", i).as_bytes()).unwrap();
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+        let contents: Vec<Vec<u8>> = follower.by_ref().take(20).map(|r| r.unwrap().content).collect();
+        appender.join().unwrap();
+
+        assert_eq!((0..20).map(|i| format!("Record number {}!", i).into_bytes()).collect::<Vec<_>>(), contents);
+        assert!(follower.next_timeout(Duration::from_millis(20)).unwrap().is_none());
+    }
+
+    #[test]
+    fn follower_should_resume_from_saved_position() {
+        let file_name = new_test_file("follower_should_resume_from_saved_position");
+        let mut writer = DiskWriter::new(&file_name, 256, DurabilityMode::EveryWrite).unwrap();
+        let positions = add_numbered_records(&mut writer, 0..5);
+
+        let options = DiskReaderOptions { skip_deleted: true, ..DiskReaderOptions::unlocked() };
+        let mut follower = DiskReader::new(&file_name, options).unwrap().follow(POLL_INTERVAL).unwrap();
+        follower.try_next().unwrap();
+        follower.try_next().unwrap();
+        let saved = follower.position();
+        assert_eq!(positions[2], saved);
+        drop(follower);
+
+        writer.add_record(b"later").unwrap();
+        writer.delete_record(positions[3]).unwrap();
+        let mut follower = DiskReader::new(&file_name, options).unwrap().follow_from(saved, POLL_INTERVAL).unwrap();
+        let mut contents = Vec::new();
+        while let Some(record) = follower.try_next().unwrap() {
+            contents.push(record.content);
+        }
+
+        assert_eq!(vec![b"Record number 2!".to_vec(), b"Record number 4!".to_vec(), b"later".to_vec()], contents);
+        assert!(DiskReader::new(&file_name, options).unwrap().follow_from(saved + 1, POLL_INTERVAL).is_err());
+        assert!(DiskReader::new(&file_name, options).unwrap().follow_from(follower.position(), POLL_INTERVAL).is_ok());
+    }
+
+    #[test]
+    fn follower_should_yield_records_appended_after_a_delete() {
+        let file_name = new_test_file("follower_should_yield_records_appended_after_a_delete");
+        let mut writer = DiskWriter::new(&file_name, 256, DurabilityMode::EveryWrite).unwrap();
+        let first = writer.add_record(b"first record").unwrap();
+        writer.add_record(b"second record").unwrap();
+        writer.delete_record(first).unwrap();
+
+        let options = DiskReaderOptions { skip_deleted: true, ..DiskReaderOptions::unlocked() };
+        let mut follower = DiskReader::new(&file_name, options).unwrap().follow(POLL_INTERVAL).unwrap();
+        assert_eq!(b"second record".to_vec(), follower.try_next().unwrap().unwrap().content);
+        assert!(follower.try_next().unwrap().is_none());
+
+        // the same size as the deleted record, it would fit in its space
+        assert!(writer.add_record(b"appended rec").unwrap() > first);
+        assert_eq!(b"appended rec".to_vec(), follower.next_timeout(Duration::from_secs(1)).unwrap().unwrap().content);
+    }
+
+    #[test]
+    fn follower_should_fail_once_space_of_deleted_records_is_reused() {
+        let file_name = new_test_file("follower_should_fail_once_space_of_deleted_records_is_reused");
+        let mut writer = DiskWriter::new(&file_name, 256, DurabilityMode::EveryWrite).unwrap();
+        writer.reuse_free_space = true;
+        let first = writer.add_record(b"first record").unwrap();
+        writer.add_record(b"second record").unwrap();
+        writer.delete_record(first).unwrap();
+
+        let mut follower = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap().follow(POLL_INTERVAL).unwrap();
+        while follower.try_next().unwrap().is_some() {}
+
+        // written before the follower position, it would be missed
+        assert_eq!(first, writer.add_record(b"reused space").unwrap());
+        assert!(matches!(follower.try_next(), Err(StorageError::ReusedSpace)));
+        assert!(matches!(DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap().follow(POLL_INTERVAL), Err(StorageError::ReusedSpace)));
+
+        // back in commit order
+        writer.compact().unwrap();
+        let mut reader = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap();
+        assert_eq!(b"second record".to_vec(), reader.next().unwrap().unwrap().content);
+        let mut follower = reader.follow(POLL_INTERVAL).unwrap();
+        assert_eq!(b"reused space".to_vec(), follower.try_next().unwrap().unwrap().content);
+    }
+
+    #[test]
+    fn follower_should_go_on_in_the_file_a_compaction_renamed_over() {
+        let file_name = new_test_file("follower_should_go_on_in_the_file_a_compaction_renamed_over");
+        let mut writer = DiskWriter::new(&file_name, 256, DurabilityMode::EveryWrite).unwrap();
+        let positions = add_numbered_records(&mut writer, 0..5);
+
+        let options = DiskReaderOptions { skip_deleted: true, ..DiskReaderOptions::unlocked() };
+        let mut follower = DiskReader::new(&file_name, options).unwrap().follow(POLL_INTERVAL).unwrap();
+        assert_eq!(b"Record number 0!".to_vec(), follower.try_next().unwrap().unwrap().content);
+        assert_eq!(b"Record number 1!".to_vec(), follower.try_next().unwrap().unwrap().content);
+
+        writer.delete_record(positions[0]).unwrap();
+        writer.delete_record(positions[3]).unwrap();
+        writer.compact().unwrap();
+        writer.add_record(b"after compaction").unwrap();
+
+        let mut contents = Vec::new();
+        while let Some(record) = follower.try_next().unwrap() {
+            contents.push(record.content);
+        }
+        assert_eq!(vec![b"Record number 2!".to_vec(), b"Record number 4!".to_vec(), b"after compaction".to_vec()], contents);
+
+        let position = follower.position();
+        writer.add_record(b"later").unwrap();
+        assert_eq!(b"later".to_vec(), follower.next_timeout(Duration::from_secs(1)).unwrap().unwrap().content);
+        assert!(position < follower.position());
+    }
+
+}
@@ -0,0 +1,537 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use bytes::{Buf, BufMut, BytesMut};
+use crate::storage::compaction::sync_parent_folder;
+use crate::storage::disk_writer::{DiskWriter, Record, RecordsFileMeta, RECORD_FLAG_METADATA};
+use crate::storage::error::StorageError;
+use crate::storage::overflow::RECORD_FLAG_MORE_CHUNKS;
+
+/// Size of the smallest record, an empty one. A free extent is never split into a piece smaller than that.
+pub const MIN_RECORD_SIZE: u64 = 8 + 4 + 1;
+/// Free extents are not coalesced beyond this size, so that the deleted record filling one
+/// always fits in the `max_record_size` of a reader.
+pub const MAX_FREE_EXTENT_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Space of deleted records that new records can take.
+/// Every extent of the map is exactly one deleted record written over the whole extent. It has no flags,
+/// besides `RECORD_FLAG_METADATA`, unless it was released while `DiskWriter::reuse_free_space` was unset.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FreeExtent {
+    pub position: u64,
+    pub size: u64
+}
+
+impl FreeExtent {
+
+    pub fn end(&self) -> u64 {
+        self.position + self.size
+    }
+
+    /// Returns true if a record of `size` bytes can be written in the extent, leaving either nothing
+    /// or enough room for the deleted record filling the rest.
+    pub fn fits(&self, size: u64) -> bool {
+        self.size == size || self.size >= size + MIN_RECORD_SIZE
+    }
+
+}
+
+/// Free extents of a data file, indexed by size class, persisted next to it in `<file>.fsm`.
+///
+/// File layout:
+///
+/// ```text
+/// | crc32   | pending | pending position | pending size | count   | extents (position, size) |
+/// | 4 bytes | 1 byte  | 8 bytes          | 8 bytes      | 8 bytes | 16 bytes per extent      |
+/// ```
+///
+/// `pending` is the extent being rewritten in place. It is saved before the data file is touched,
+/// so that the writer can repair the extent when a crash interrupted the rewrite.
+pub struct FreeSpaceMap {
+    pub file_name: String,
+    pub pending: Option<FreeExtent>,
+    by_position: BTreeMap<u64, u64>,
+    by_class: BTreeMap<u32, BTreeSet<u64>>
+}
+
+impl FreeSpaceMap {
+
+    pub fn file_name_of(data_file_name: &str) -> String {
+        format!("{}.fsm", data_file_name)
+    }
+
+    /// Extents of `size` bytes are in the class `floor(log2(size)) + 1`.
+    pub fn size_class(size: u64) -> u32 {
+        64 - size.leading_zeros()
+    }
+
+    pub fn empty(file_name: &str) -> FreeSpaceMap {
+        FreeSpaceMap { file_name: String::from(file_name), pending: None, by_position: BTreeMap::new(), by_class: BTreeMap::new() }
+    }
+
+    /// Loads the map. A missing or damaged map is replaced by an empty one: it only loses the reuse of some space.
+    pub fn load(file_name: &str) -> Result<FreeSpaceMap, StorageError> {
+        let mut map = FreeSpaceMap::empty(file_name);
+        if !Path::new(file_name).exists() {
+            return Ok(map);
+        }
+
+        let content = std::fs::read(file_name)?;
+        if content.len() < 4 + 1 + 8 + 8 + 8 || crc32fast::hash(&content[4..]) != u32::from_be_bytes(content[..4].try_into().unwrap()) {
+            return Ok(map);
+        }
+
+        let mut buf = &content[4..];
+        let has_pending = buf.get_u8() != 0;
+        let pending = FreeExtent { position: buf.get_u64(), size: buf.get_u64() };
+        let count = buf.get_u64();
+        if buf.remaining() as u64 != count * 16 {
+            return Ok(map);
+        }
+
+        map.pending = if has_pending { Some(pending) } else { None };
+        for _ in 0..count {
+            map.insert(FreeExtent { position: buf.get_u64(), size: buf.get_u64() });
+        }
+        Ok(map)
+    }
+
+    /// Writes the map under a temporary name and renames it over the previous one.
+    pub fn save(&self) -> Result<(), StorageError> {
+        let pending = self.pending.unwrap_or(FreeExtent { position: 0, size: 0 });
+        let mut buf = BytesMut::with_capacity(4 + 1 + 8 + 8 + 8 + self.by_position.len() * 16);
+        buf.put_u32(0);
+        buf.put_u8(self.pending.is_some() as u8);
+        buf.put_u64(pending.position);
+        buf.put_u64(pending.size);
+        buf.put_u64(self.by_position.len() as u64);
+        for (position, size) in &self.by_position {
+            buf.put_u64(*position);
+            buf.put_u64(*size);
+        }
+        let checksum = crc32fast::hash(&buf[4..]);
+        buf[..4].copy_from_slice(&checksum.to_be_bytes());
+
+        let temp_file_name = format!("{}.tmp", self.file_name);
+        let mut file = File::create(&temp_file_name)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_file_name, &self.file_name)?;
+        sync_parent_folder(&self.file_name);
+        Ok(())
+    }
+
+    pub fn extents(&self) -> Vec<FreeExtent> {
+        self.by_position.iter().map(|(position, size)| FreeExtent { position: *position, size: *size }).collect()
+    }
+
+    pub fn insert(&mut self, extent: FreeExtent) {
+        self.by_position.insert(extent.position, extent.size);
+        self.by_class.entry(FreeSpaceMap::size_class(extent.size)).or_default().insert(extent.position);
+    }
+
+    pub fn remove(&mut self, position: u64) -> Option<FreeExtent> {
+        let size = self.by_position.remove(&position)?;
+        let class = FreeSpaceMap::size_class(size);
+        if let Some(positions) = self.by_class.get_mut(&class) {
+            positions.remove(&position);
+            if positions.is_empty() {
+                self.by_class.remove(&class);
+            }
+        }
+        Some(FreeExtent { position, size })
+    }
+
+    /// The extent ending right where `position` starts.
+    pub fn extent_ending_at(&self, position: u64) -> Option<FreeExtent> {
+        let (start, size) = self.by_position.range(..position).next_back()?;
+        if start + size == position { Some(FreeExtent { position: *start, size: *size }) } else { None }
+    }
+
+    /// First extent a record of `size` bytes fits in, looking from its own size class upwards.
+    pub fn find(&self, size: u64) -> Option<FreeExtent> {
+        self.by_class.range(FreeSpaceMap::size_class(size)..)
+            .flat_map(|(_, positions)| positions.iter())
+            .map(|position| FreeExtent { position: *position, size: self.by_position[position] })
+            .find(|extent| extent.fits(size))
+    }
+
+    /// Forgets the extents past `end`, after the data file was truncated.
+    pub fn truncate(&mut self, end: u64) {
+        let past_end: Vec<u64> = self.by_position.iter()
+            .filter(|(position, size)| *position + *size > end)
+            .map(|(position, _)| *position)
+            .collect();
+        for position in past_end {
+            self.remove(position);
+        }
+    }
+
+}
+
+/// Returns true if the record has no flags but `RECORD_FLAG_METADATA`: once deleted, it stands for its extent as is.
+fn is_plain(record: &Record) -> bool {
+    record.flags & !RECORD_FLAG_METADATA == 0
+}
+
+/// Splits `extent` in pieces no larger than `MAX_FREE_EXTENT_SIZE`.
+fn split_extent(extent: FreeExtent) -> Vec<FreeExtent> {
+    let mut pieces = Vec::new();
+    let mut position = extent.position;
+    let mut remaining = extent.size;
+
+    while remaining > MAX_FREE_EXTENT_SIZE {
+        let size = if remaining - MAX_FREE_EXTENT_SIZE >= MIN_RECORD_SIZE { MAX_FREE_EXTENT_SIZE } else { remaining - MIN_RECORD_SIZE };
+        pieces.push(FreeExtent { position, size });
+        position += size;
+        remaining -= size;
+    }
+    pieces.push(FreeExtent { position, size: remaining });
+    pieces
+}
+
+impl DiskWriter {
+
+    /// Deleted record covering `size` bytes from `position`, its content is zeroed.
+    fn filler(position: u64, size: u64) -> Record {
+        let mut record = Record::new(position, &vec![0; (size - MIN_RECORD_SIZE) as usize]);
+        record.deleted = true;
+        record
+    }
+
+    /// Overwrites `extent` with deleted records and returns the free extents they form.
+    /// The extent is saved as pending first, the caller clears it once the new metadata is committed.
+    fn write_fillers(&mut self, extent: FreeExtent) -> Result<Vec<FreeExtent>, StorageError> {
+        self.free_space.pending = Some(extent);
+        self.free_space.save()?;
+
+        let pieces = split_extent(extent);
+        let mut buf = Vec::with_capacity(extent.size as usize);
+        for piece in &pieces {
+            buf.extend_from_slice(&DiskWriter::filler(piece.position, piece.size).to_bytes());
+        }
+        (&self.file).seek(SeekFrom::Start(extent.position))?;
+        (&self.file).write_all(&buf)?;
+        self.fsync()?;
+
+        Ok(pieces)
+    }
+
+    /// Size of the record at `position` including all of its chunks.
+    pub(crate) fn record_extent_size(&self, record: &Record) -> Result<u64, StorageError> {
+        let mut size = record.size();
+        let mut more = record.flags & RECORD_FLAG_MORE_CHUNKS != 0;
+        let end = self.meta.get().position;
+
+        while more {
+            let position = record.position + size;
+            (&self.file).seek(SeekFrom::Start(position))?;
+            let chunk = Record::read_from(&self.file, end.saturating_sub(position + MIN_RECORD_SIZE))?;
+            size += chunk.size();
+            more = chunk.flags & RECORD_FLAG_MORE_CHUNKS != 0;
+        }
+        Ok(size)
+    }
+
+    /// Adds the space of the deleted `record` to the free-space map, merging it with the free extents around it.
+    /// When that spans several records, they are rewritten as deleted records covering the merged extent.
+    pub(crate) fn release_record(&mut self, record: &Record) -> Result<(), StorageError> {
+        let size = self.record_extent_size(record)?;
+        let mut extent = FreeExtent { position: record.position, size };
+        let mut records_count = 1;
+
+        if let Some(previous) = self.free_space.extent_ending_at(extent.position).filter(|p| p.size + extent.size <= MAX_FREE_EXTENT_SIZE) {
+            self.free_space.remove(previous.position);
+            extent = FreeExtent { position: previous.position, size: previous.size + extent.size };
+            records_count += 1;
+        }
+        if let Some(next) = self.free_space.remove(extent.end()) {
+            if next.size + extent.size <= MAX_FREE_EXTENT_SIZE {
+                extent.size += next.size;
+                records_count += 1;
+            } else {
+                self.free_space.insert(next);
+            }
+        }
+
+        if records_count == 1 && is_plain(record) {
+            self.free_space.insert(extent);
+            return self.free_space.save();
+        }
+
+        let pieces = self.write_fillers(extent)?;
+        let meta = self.meta.get();
+        self.commit(RecordsFileMeta { records_count: meta.records_count + pieces.len() as u64 - records_count, ..meta }, 0)?;
+
+        self.free_space.pending = None;
+        for piece in pieces {
+            self.free_space.insert(piece);
+        }
+        self.free_space.save()
+    }
+
+    /// Adds the space of the deleted `record` to the free-space map as is, without merging it or rewriting anything,
+    /// so that it can be reused once `reuse_free_space` is set.
+    pub(crate) fn keep_released_record(&mut self, record: &Record) -> Result<(), StorageError> {
+        let size = self.record_extent_size(record)?;
+        self.free_space.insert(FreeExtent { position: record.position, size });
+        self.free_space.save()
+    }
+
+    /// Returns the free extent `size` bytes fit in, after checking it still holds the deleted record the map expects.
+    /// Extents that do not are dropped from the map.
+    fn take_free_extent(&mut self, size: u64) -> Result<Option<FreeExtent>, StorageError> {
+        while let Some(extent) = self.free_space.find(size) {
+            self.free_space.remove(extent.position);
+
+            (&self.file).seek(SeekFrom::Start(extent.position))?;
+            let record = match Record::read_from(&self.file, extent.size - MIN_RECORD_SIZE) {
+                Ok(record) if record.deleted => record,
+                Err(StorageError::Io(e)) => return Err(StorageError::Io(e)),
+                _ => continue
+            };
+            match self.record_extent_size(&record) {
+                Ok(size) if size == extent.size => return Ok(Some(extent)),
+                Err(StorageError::Io(e)) => return Err(StorageError::Io(e)),
+                _ => continue
+            }
+        }
+        Ok(None)
+    }
+
+    /// Writes `buf` in the space of a deleted record if one is large enough.
+    /// Returns the position of the record, or `None` if it must be appended.
+    ///
+    /// Records written in place are synced whatever the durability mode, the extent cannot be
+    /// left half written once the map no longer marks it as pending.
+    pub(crate) fn add_record_in_free_space(&mut self, buf: &[u8]) -> Result<Option<u64>, StorageError> {
+        if self.free_space.extents().is_empty() {
+            return Ok(None);
+        }
+
+        let (lsn, timestamp) = (self.next_lsn(), Record::now_timestamp());
+        let size: u64 = self.encode_chunks(self.meta.get().position, buf, lsn, timestamp)?.iter().map(|c| c.size()).sum();
+        let extent = match self.take_free_extent(size)? {
+            Some(extent) => extent,
+            None => return Ok(None)
+        };
+
+        // encrypted contents are bound to their position
+        let chunks = self.encode_chunks(extent.position, buf, lsn, timestamp)?;
+        let mut bytes = Vec::with_capacity(extent.size as usize);
+        for chunk in &chunks {
+            bytes.extend_from_slice(&chunk.to_bytes());
+        }
+        let remainder = FreeExtent { position: extent.position + bytes.len() as u64, size: extent.size - bytes.len() as u64 };
+        if remainder.size > 0 {
+            bytes.extend_from_slice(&DiskWriter::filler(remainder.position, remainder.size).to_bytes());
+        }
+
+        self.free_space.pending = Some(extent);
+        self.free_space.save()?;
+        (&self.file).seek(SeekFrom::Start(extent.position))?;
+        (&self.file).write_all(&bytes)?;
+        self.fsync()?;
+
+        let mut meta = self.meta.get();
+        meta.reused_space = true;
+        meta.last_lsn = meta.last_lsn.max(lsn);
+        if remainder.size > 0 {
+            meta.records_count += 1;
+        }
+        self.commit(meta, 1)?;
+
+        self.free_space.pending = None;
+        if remainder.size > 0 {
+            self.free_space.insert(remainder);
+        }
+        self.free_space.save()?;
+        Ok(Some(extent.position))
+    }
+
+    /// Repairs the extent a crash interrupted the rewrite of. If the records written in it are all valid
+    /// they are kept, otherwise the extent is filled with deleted records again.
+    /// `recover` runs afterwards and fixes the records count.
+    pub(crate) fn repair_pending_free_extent(&mut self) -> Result<(), StorageError> {
+        let extent = match self.free_space.pending {
+            Some(extent) => extent,
+            None => return Ok(())
+        };
+
+        let mut position = extent.position;
+        let mut free = Vec::new();
+        (&self.file).seek(SeekFrom::Start(position))?;
+        let mut complete = true;
+        let mut live = false;
+        while position < extent.end() {
+            match Record::read_from(&self.file, (extent.end() - position).saturating_sub(MIN_RECORD_SIZE)) {
+                Ok(record) => {
+                    if record.deleted && is_plain(&record) {
+                        free.push(FreeExtent { position, size: record.size() });
+                    }
+                    live |= !record.deleted;
+                    position += record.size();
+                },
+                Err(StorageError::Io(e)) => return Err(StorageError::Io(e)),
+                Err(_) => {
+                    complete = false;
+                    break;
+                }
+            }
+        }
+
+        if !complete || position != extent.end() {
+            free = self.write_fillers(extent)?;
+        } else if live && !self.meta.get().reused_space {
+            let meta = self.meta.get();
+            self.write_metadata_and_fsync(RecordsFileMeta { reused_space: true, ..meta })?;
+        }
+
+        self.free_space.pending = None;
+        for piece in free {
+            self.free_space.insert(piece);
+        }
+        self.free_space.save()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::disk_reader::DiskReaderOptions;
+    use crate::storage::disk_writer::RECORD_METADATA_SIZE;
+    use crate::storage::disk_writer::tests::{new_test_file, read_contents};
+    use crate::storage::durability::DurabilityMode;
+
+    fn contents(file_name: &str) -> Vec<Vec<u8>> {
+        read_contents(file_name, DiskReaderOptions { skip_deleted: true, ..DiskReaderOptions::unlocked() })
+    }
+
+    #[test]
+    fn add_record_should_reuse_space_of_deleted_record() {
+        let file_name = new_test_file("add_record_should_reuse_space_of_deleted_record");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.reuse_free_space = true;
+        writer.add_record(b"first").unwrap();
+        let second = writer.add_record(b"second record, quite a long one").unwrap();
+        writer.add_record(b"third").unwrap();
+        let end = writer.meta.get().position;
+
+        writer.delete_record(second).unwrap();
+        let same_size = writer.add_record(b"second record, reused in place!").unwrap();
+
+        assert_eq!(second, same_size);
+        assert_eq!(end, writer.meta.get().position);
+        assert_eq!(vec![b"first".to_vec(), b"second record, reused in place!".to_vec(), b"third".to_vec()], contents(&file_name));
+    }
+
+    #[test]
+    fn smaller_record_should_split_free_extent() {
+        let file_name = new_test_file("smaller_record_should_split_free_extent");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.reuse_free_space = true;
+        let big = writer.add_record(&[1; 200]).unwrap();
+        writer.add_record(b"last").unwrap();
+        let end = writer.meta.get().position;
+
+        writer.delete_record(big).unwrap();
+        let first = writer.add_record(&[2; 50]).unwrap();
+        let second = writer.add_record(&[3; 50]).unwrap();
+
+        assert_eq!(big, first);
+        assert_eq!(first + 50 + RECORD_METADATA_SIZE + MIN_RECORD_SIZE, second);
+        assert_eq!(end, writer.meta.get().position);
+        assert_eq!(vec![vec![2; 50], vec![3; 50], b"last".to_vec()], contents(&file_name));
+        assert_eq!(4, writer.meta.get().records_count);
+    }
+
+    #[test]
+    fn adjacent_deleted_records_should_be_coalesced() {
+        let file_name = new_test_file("adjacent_deleted_records_should_be_coalesced");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.reuse_free_space = true;
+        let positions: Vec<u64> = (0..3).map(|_| writer.add_record(&[1; 30]).unwrap()).collect();
+        writer.add_record(b"last").unwrap();
+
+        writer.delete_record(positions[0]).unwrap();
+        writer.delete_record(positions[2]).unwrap();
+        writer.delete_record(positions[1]).unwrap();
+
+        assert_eq!(vec![FreeExtent { position: positions[0], size: 3 * (30 + RECORD_METADATA_SIZE + MIN_RECORD_SIZE) }], writer.free_space.extents());
+        assert_eq!(2, writer.meta.get().records_count);
+
+        let position = writer.add_record(&[5; 100]).unwrap();
+        assert_eq!(positions[0], position);
+        assert_eq!(vec![vec![5; 100], b"last".to_vec()], contents(&file_name));
+    }
+
+    #[test]
+    fn free_space_map_should_survive_reopening() {
+        let file_name = new_test_file("free_space_map_should_survive_reopening");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.reuse_free_space = true;
+        let first = writer.add_record(&[1; 100]).unwrap();
+        writer.add_record(b"last").unwrap();
+        writer.delete_record(first).unwrap();
+        drop(writer);
+
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+
+        writer.reuse_free_space = true;
+        assert_eq!(first, writer.add_record(&[2; 100]).unwrap());
+    }
+
+    #[test]
+    fn records_deleted_without_reuse_should_be_reused_once_it_is_set() {
+        let file_name = new_test_file("records_deleted_without_reuse_should_be_reused_once_it_is_set");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.max_chunk_size = 100;
+        let plain = writer.add_record(&[1; 50]).unwrap();
+        let chunked = writer.add_record(&[2; 250]).unwrap();
+        writer.add_record(b"last").unwrap();
+        let end = writer.meta.get().position;
+
+        writer.delete_record(plain).unwrap();
+        writer.delete_record(chunked).unwrap();
+        let chunked_size = end - chunked - (4 + RECORD_METADATA_SIZE + MIN_RECORD_SIZE);
+        assert_eq!(vec![FreeExtent { position: plain, size: 50 + RECORD_METADATA_SIZE + MIN_RECORD_SIZE }, FreeExtent { position: chunked, size: chunked_size }], writer.free_space.extents());
+        assert!(!writer.meta.get().reused_space);
+        drop(writer);
+
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.max_chunk_size = 100;
+        writer.reuse_free_space = true;
+
+        assert_eq!(plain, writer.add_record(&[3; 50]).unwrap());
+        assert_eq!(chunked, writer.add_record(&[4; 200]).unwrap());
+        assert_eq!(end, writer.meta.get().position);
+        assert_eq!(vec![vec![3; 50], vec![4; 200], b"last".to_vec()], contents(&file_name));
+    }
+
+    #[test]
+    fn interrupted_rewrite_of_free_extent_should_be_repaired_on_open() {
+        let file_name = new_test_file("interrupted_rewrite_of_free_extent_should_be_repaired_on_open");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.reuse_free_space = true;
+        let first = writer.add_record(&[1; 100]).unwrap();
+        writer.add_record(b"last").unwrap();
+        writer.delete_record(first).unwrap();
+
+        // crash in the middle of writing a record in the free extent
+        let extent = writer.free_space.extents()[0];
+        writer.free_space.pending = Some(extent);
+        writer.free_space.save().unwrap();
+        (&writer.file).seek(SeekFrom::Start(extent.position)).unwrap();
+        (&writer.file).write_all(&Record::new(extent.position, &[9; 100]).to_bytes()[..50]).unwrap();
+        drop(writer);
+
+        let writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+
+        assert_eq!(vec![extent], writer.free_space.extents());
+        assert_eq!(None, writer.free_space.pending);
+        assert_eq!(vec![b"last".to_vec()], contents(&file_name));
+    }
+
+}
@@ -0,0 +1,169 @@
+use std::fs::{File, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::storage::error::StorageError;
+
+/// Delay between two attempts to lock a file in `FileLock::Wait` mode.
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// How a data file is locked when it is opened.
+///
+/// Locks are advisory (flock on Unix, LockFileEx on Windows): writers take an exclusive lock, readers a shared one
+/// by default, and a handle opened with `FileLock::None` ignores them. They are released when the handle is closed.
+/// Two handles of the same process exclude each other too, so a reader opened next to a writer of the same process
+/// must not lock the file, see `DiskReaderOptions::unlocked`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileLock {
+    /// The file is not locked.
+    None,
+    /// Opening fails with `StorageError::Locked` if the file is locked in a conflicting mode.
+    Fail,
+    /// Opening waits up to the duration for a conflicting lock to be released, then fails with `StorageError::Locked`.
+    Wait(Duration)
+}
+
+/// Locks `file`, exclusively for a writer or shared for a reader, according to `mode`.
+pub fn lock_file(file: &File, file_name: &str, exclusive: bool, mode: FileLock) -> Result<(), StorageError> {
+    let timeout = match mode {
+        FileLock::None => return Ok(()),
+        FileLock::Fail => Duration::ZERO,
+        FileLock::Wait(timeout) => timeout
+    };
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let result = if exclusive { file.try_lock() } else { file.try_lock_shared() };
+        match result {
+            Ok(()) => return Ok(()),
+            Err(TryLockError::Error(e)) => return Err(StorageError::Io(e)),
+            Err(TryLockError::WouldBlock) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(StorageError::Locked(String::from(file_name)));
+                }
+                thread::sleep(LOCK_RETRY_DELAY.min(deadline - now));
+            }
+        }
+    }
+}
+
+/// Returns true if `file` is still the file named `file_name`, and not one a compaction or an upgrade renamed
+/// a new file over since it was opened. Always true where files cannot be renamed over while open.
+pub fn is_same_file(file: &File, file_name: &str) -> Result<bool, StorageError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let current = match std::fs::metadata(file_name) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(StorageError::Io(e))
+        };
+        let opened = file.metadata()?;
+        Ok(opened.dev() == current.dev() && opened.ino() == current.ino())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (file, file_name);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::disk_reader::{DiskReader, DiskReaderOptions};
+    use crate::storage::disk_writer::DiskWriter;
+    use crate::storage::disk_writer::tests::{add_numbered_records, new_test_file};
+    use crate::storage::durability::DurabilityMode;
+
+    fn locked_reader_options(lock: FileLock) -> DiskReaderOptions {
+        DiskReaderOptions { lock, ..DiskReaderOptions::unlocked() }
+    }
+
+    #[test]
+    fn second_writer_should_fail_while_file_is_locked() {
+        let file_name = new_test_file("second_writer_should_fail_while_file_is_locked");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.add_record(b"hello").unwrap();
+
+        assert!(matches!(DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite), Err(StorageError::Locked(ref name)) if *name == file_name));
+        assert!(matches!(DiskReader::new(&file_name, DiskReaderOptions::create_default()), Err(StorageError::Locked(_))));
+        // an unlocked reader ignores the lock of the writer
+        assert_eq!(1, DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap().count());
+
+        drop(writer);
+        assert!(DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).is_ok());
+    }
+
+    #[test]
+    fn readers_should_share_the_lock_and_exclude_writers() {
+        let file_name = new_test_file("readers_should_share_the_lock_and_exclude_writers");
+        drop(DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap());
+
+        let first = DiskReader::new(&file_name, DiskReaderOptions::create_default()).unwrap();
+        let second = DiskReader::new(&file_name, locked_reader_options(FileLock::Fail)).unwrap();
+        assert!(matches!(DiskWriter::new_with_lock(&file_name, 2048, DurabilityMode::EveryWrite, None, FileLock::Fail), Err(StorageError::Locked(_))));
+
+        drop(first);
+        drop(second);
+        assert!(DiskWriter::new_with_lock(&file_name, 2048, DurabilityMode::EveryWrite, None, FileLock::Fail).is_ok());
+    }
+
+    #[test]
+    fn waiting_writer_should_get_the_lock_once_released_or_time_out() {
+        let file_name = new_test_file("waiting_writer_should_get_the_lock_once_released_or_time_out");
+        let writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+
+        let started = Instant::now();
+        let result = DiskWriter::new_with_lock(&file_name, 2048, DurabilityMode::EveryWrite, None, FileLock::Wait(Duration::from_millis(50)));
+        assert!(matches!(result, Err(StorageError::Locked(_))));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(writer);
+        });
+        let result = DiskWriter::new_with_lock(&file_name, 2048, DurabilityMode::EveryWrite, None, FileLock::Wait(Duration::from_secs(10)));
+        releaser.join().unwrap();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn waiting_writer_should_keep_the_records_written_meanwhile() {
+        let file_name = new_test_file("waiting_writer_should_keep_the_records_written_meanwhile");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+
+        let waiting_file_name = file_name.clone();
+        let waiting = thread::spawn(move || DiskWriter::new_with_lock(&waiting_file_name, 1024, DurabilityMode::EveryWrite, None, FileLock::Wait(Duration::from_secs(10))));
+        thread::sleep(Duration::from_millis(50));
+        add_numbered_records(&mut writer, 0..10);
+        drop(writer);
+
+        let writer = waiting.join().unwrap().unwrap();
+        assert_eq!(10, writer.meta.get().records_count);
+        drop(writer);
+        assert_eq!(10, DiskReader::new(&file_name, DiskReaderOptions::create_default()).unwrap().count());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn waiting_writer_should_open_the_file_renamed_over_the_locked_one() {
+        let file_name = new_test_file("waiting_writer_should_open_the_file_renamed_over_the_locked_one");
+        let replacement_file_name = format!("{}.replacement", file_name);
+        let mut replacement = DiskWriter::new(&replacement_file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        replacement.add_record(b"replacement").unwrap();
+        drop(replacement);
+        let writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+
+        let waiting_file_name = file_name.clone();
+        let waiting = thread::spawn(move || DiskWriter::new_with_lock(&waiting_file_name, 2048, DurabilityMode::EveryWrite, None, FileLock::Wait(Duration::from_secs(10))));
+        thread::sleep(Duration::from_millis(50));
+        // like a compaction, the new file replaces the original one while it is still locked
+        std::fs::rename(&replacement_file_name, &file_name).unwrap();
+        drop(writer);
+
+        let writer = waiting.join().unwrap().unwrap();
+        assert_eq!(1, writer.meta.get().records_count);
+    }
+
+}
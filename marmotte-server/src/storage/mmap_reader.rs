@@ -0,0 +1,315 @@
+use std::borrow::Cow;
+use std::fs::{File, OpenOptions};
+use memmap2::Mmap;
+use crate::storage::disk_reader::DiskReaderOptions;
+use crate::storage::disk_writer::{Record, RecordsFileMeta, RECORD_FLAG_METADATA, RECORD_LENGTH_MASK, RECORD_METADATA_SIZE};
+use crate::storage::error::StorageError;
+use crate::storage::locking::{is_same_file, lock_file};
+use crate::storage::overflow::{RECORD_FLAG_CONTINUATION, RECORD_FLAG_MORE_CHUNKS};
+
+/// Record read from a memory mapped file. A record stored as is borrows its content from the mapping,
+/// one that is compressed, encrypted or split in chunks is decoded into an owned buffer.
+pub struct RecordView<'a> {
+    pub position: u64,
+    pub content: Cow<'a, [u8]>,
+    pub deleted: bool,
+    /// Size of the record in the file, including all of its chunks.
+    extent_size: u64
+}
+
+/// Reads a data file through a read-only memory mapping: no syscall and no copy per record.
+///
+/// The mapping covers the file as it was when mapped or last refreshed, `refresh` maps it again once the writer
+/// committed more records, or maps the new file once a compaction or an upgrade replaced it.
+pub struct MmapReader {
+    pub file_name: String,
+    pub file: File,
+    pub meta: RecordsFileMeta,
+    pub options: DiskReaderOptions,
+    map: Mmap
+}
+
+impl MmapReader {
+
+    pub fn new(file_name: &str, mut options: DiskReaderOptions) -> Result<MmapReader, StorageError> {
+        let mut file = OpenOptions::new().read(true).open(file_name)?;
+        lock_file(&file, file_name, false, options.lock)?;
+        // the metadata is read before mapping, so the mapping covers the records it points to
+        let meta = MmapReader::read_metadata(&mut file, &mut options)?;
+        Ok(MmapReader { file_name: String::from(file_name), map: MmapReader::map(&file)?, file, meta, options })
+    }
+
+    /// Reads the committed metadata of `file` and checks the key of `options` against it.
+    fn read_metadata(file: &mut File, options: &mut DiskReaderOptions) -> Result<RecordsFileMeta, StorageError> {
+        let meta = RecordsFileMeta::read_metadata(file)?;
+        meta.check_key(options.encryption_key.as_ref())?;
+        if meta.key_id.is_none() {
+            options.encryption_key = None;
+        }
+        Ok(meta)
+    }
+
+    fn map(file: &File) -> Result<Mmap, StorageError> {
+        // SAFETY: only the committed records are read, and writers never shrink a file below its committed records:
+        // they only grow it, and recovery truncates uncommitted bytes. A compaction or an upgrade renames a new file
+        // over this one, the mapped file is left as is. A file truncated by anything else makes reading the mapping fault.
+        // A record deleted or rewritten in place while mapped may change under a view, its checksum is checked
+        // when it is parsed, not after.
+        Ok(unsafe { Mmap::map(file)? })
+    }
+
+    /// Reloads the committed metadata and maps the file again if it grew past the mapping or shrank.
+    /// Once a compaction or an upgrade replaced the file, opens and maps the new one: the positions of the records change.
+    /// Returns true when new records were committed since the last refresh, or the file was replaced.
+    pub fn refresh(&mut self) -> Result<bool, StorageError> {
+        if !is_same_file(&self.file, &self.file_name)? {
+            let mut file = OpenOptions::new().read(true).open(&self.file_name)?;
+            lock_file(&file, &self.file_name, false, self.options.lock)?;
+            let meta = MmapReader::read_metadata(&mut file, &mut self.options)?;
+            self.map = MmapReader::map(&file)?;
+            self.file = file;
+            self.meta = meta;
+            return Ok(true);
+        }
+
+        let previous = self.meta.position;
+        self.meta = MmapReader::read_metadata(&mut self.file, &mut self.options)?;
+        let len = self.file.metadata()?.len();
+        if self.meta.position > self.map.len() as u64 || len < self.map.len() as u64 {
+            self.map = MmapReader::map(&self.file)?;
+        }
+        Ok(self.meta.position != previous)
+    }
+
+    /// Committed part of the mapping.
+    fn committed(&self) -> &[u8] {
+        let end = (self.meta.position as usize).min(self.map.len());
+        &self.map[..end]
+    }
+
+    /// Parses the record or chunk starting at `position` without copying it.
+    fn parse(&self, position: u64) -> Result<Record, StorageError> {
+        let data = self.committed();
+        let min_record_size = Record::deleted_flag_offset(0) + 1;
+        // no file holds a record there, the position was read from corrupted data
+        let Some(record_end) = position.checked_add(min_record_size) else {
+            return Err(StorageError::Corrupted { position });
+        };
+        if position < RecordsFileMeta::size() as u64 || record_end > data.len() as u64 {
+            return Err(StorageError::InvalidPosition(position));
+        }
+
+        let start = position as usize;
+        let prefix = u64::from_be_bytes(data[start..start + 8].try_into().unwrap());
+        let hash = u32::from_be_bytes(data[start + 8..start + 12].try_into().unwrap());
+        let flags = (prefix >> 56) as u8;
+        let len = prefix & RECORD_LENGTH_MASK;
+
+        if len > self.options.max_record_size {
+            return Err(StorageError::RecordTooLarge { position, size: len, max_size: self.options.max_record_size });
+        }
+        if record_end.checked_add(len).is_none_or(|end| end > data.len() as u64) {
+            // the length prefix goes past the committed records, this is not the start of a record
+            return Err(StorageError::InvalidPosition(position));
+        }
+
+        let content = &data[start + 12..start + 12 + len as usize];
+        let checksum = Record::compute_checksum(flags, content);
+        if checksum != hash || (flags & RECORD_FLAG_METADATA != 0 && len < RECORD_METADATA_SIZE) {
+            return Err(StorageError::Corrupted { position });
+        }
+
+        let (lsn, timestamp) = Record::metadata_of(flags, content);
+        Ok(Record { position, content_size: len, content: Vec::new(), deleted: data[start + 12 + len as usize] != 0, checksum, flags, lsn, timestamp })
+    }
+
+    fn content_of(&self, record: &Record) -> &[u8] {
+        let start = record.position as usize + 12;
+        &self.committed()[start..start + record.content_size as usize]
+    }
+
+    /// Reads the record starting at `position`, as returned by `DiskWriter::add_record`.
+    pub fn read_record_at(&self, position: u64) -> Result<RecordView<'_>, StorageError> {
+        let head = self.parse(position)?;
+        if head.flags & RECORD_FLAG_CONTINUATION != 0 {
+            return Err(StorageError::InvalidPosition(position));
+        }
+        self.view(head)
+    }
+
+    fn view(&self, head: Record) -> Result<RecordView<'_>, StorageError> {
+        let key = self.options.encryption_key.as_ref();
+        let mut extent_size = head.size();
+
+        let content = if head.flags & !RECORD_FLAG_METADATA == 0 && key.is_none() {
+            let metadata_size = if head.flags == 0 { 0 } else { RECORD_METADATA_SIZE as usize };
+            Cow::Borrowed(&self.content_of(&head)[metadata_size..])
+        } else {
+            let mut content = Record { content: self.content_of(&head).to_vec(), ..head.clone() }.decode(key)?.content;
+            let mut more = head.flags & RECORD_FLAG_MORE_CHUNKS != 0;
+
+            while more {
+                let position = head.position + extent_size;
+                let chunk = self.parse(position).map_err(|e| match e {
+                    StorageError::InvalidPosition(_) => StorageError::Corrupted { position },
+                    e => e
+                })?;
+                if chunk.flags & RECORD_FLAG_CONTINUATION == 0 {
+                    return Err(StorageError::Corrupted { position });
+                }
+                extent_size += chunk.size();
+                more = chunk.flags & RECORD_FLAG_MORE_CHUNKS != 0;
+                content.extend_from_slice(&Record { content: self.content_of(&chunk).to_vec(), ..chunk }.decode(key)?.content);
+            }
+            Cow::Owned(content)
+        };
+
+        Ok(RecordView { position: head.position, content, deleted: head.deleted, extent_size })
+    }
+
+    /// Iterates over the committed records, in file order.
+    pub fn records(&self) -> RecordViews<'_> {
+        RecordViews { reader: self, position: RecordsFileMeta::size() as u64 }
+    }
+
+}
+
+/// Iterator returned by `MmapReader::records`.
+pub struct RecordViews<'a> {
+    reader: &'a MmapReader,
+    position: u64
+}
+
+impl<'a> Iterator for RecordViews<'a> {
+
+    type Item = Result<RecordView<'a>, StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let end = self.reader.meta.position;
+
+        loop {
+            if self.position >= end {
+                return None;
+            }
+
+            let result = self.reader.parse(self.position).and_then(|head| {
+                if head.flags & RECORD_FLAG_CONTINUATION != 0 {
+                    // the first chunks of this record are missing
+                    return Err(StorageError::Corrupted { position: head.position });
+                }
+                self.reader.view(head)
+            });
+
+            match result {
+                Err(e) => {
+                    // as for `DiskReader`, the iteration ends with the error
+                    self.position = end;
+                    return Some(Err(e));
+                },
+                Ok(view) => {
+                    self.position += view.extent_size;
+                    if self.reader.options.skip_deleted && view.deleted {
+                        continue;
+                    }
+                    return Some(Ok(view));
+                }
+            }
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, SeekFrom, Write};
+    use super::*;
+    use crate::storage::compression::Compression;
+    use crate::storage::disk_reader::DiskReader;
+    use crate::storage::disk_writer::DiskWriter;
+    use crate::storage::disk_writer::tests::{add_numbered_records, new_test_file};
+    use crate::storage::durability::DurabilityMode;
+
+    #[test]
+    fn mmap_reader_should_return_same_records_as_disk_reader() {
+        let file_name = new_test_file("mmap_reader_should_return_same_records_as_disk_reader");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        add_numbered_records(&mut writer, 0..50);
+        writer.compression = Compression::Lz4;
+        writer.max_chunk_size = 100;
+        let big = writer.add_record(&"compressed and chunked ".repeat(40).into_bytes()).unwrap();
+
+        let reader = MmapReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap();
+        let views: Vec<RecordView> = reader.records().map(|r| r.unwrap()).collect();
+        let records: Vec<Box<Record>> = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap().map(|r| r.unwrap()).collect();
+
+        assert_eq!(records.len(), views.len());
+        for (view, record) in views.iter().zip(records.iter()) {
+            assert_eq!(record.position, view.position);
+            assert_eq!(record.content, view.content.as_ref());
+        }
+        assert!(matches!(views[0].content, Cow::Borrowed(_)));
+        assert!(matches!(reader.read_record_at(big).unwrap().content, Cow::Owned(_)));
+        assert!(matches!(reader.read_record_at(u64::MAX), Err(StorageError::Corrupted { position: u64::MAX })));
+    }
+
+    #[test]
+    fn refresh_should_map_records_committed_later() {
+        let file_name = new_test_file("refresh_should_map_records_committed_later");
+        let mut writer = DiskWriter::new(&file_name, 128, DurabilityMode::EveryWrite).unwrap();
+        writer.add_record(b"first").unwrap();
+
+        let mut reader = MmapReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap();
+        assert_eq!(1, reader.records().count());
+
+        let position = writer.add_record(&[7; 5000]).unwrap();
+        assert!(reader.read_record_at(position).is_err());
+        assert!(reader.refresh().unwrap());
+
+        assert_eq!(vec![7; 5000], reader.read_record_at(position).unwrap().content.as_ref());
+        assert_eq!(2, reader.records().count());
+        assert!(!reader.refresh().unwrap());
+    }
+
+    #[test]
+    fn mmap_iteration_should_report_corrupted_record_then_stop() {
+        let file_name = new_test_file("mmap_iteration_should_report_corrupted_record_then_stop");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.add_record(b"first").unwrap();
+        let second = writer.add_record(b"second").unwrap();
+        writer.add_record(b"third").unwrap();
+
+        (&writer.file).seek(SeekFrom::Start(second + 8 + 4)).unwrap();
+        (&writer.file).write_all(b"X").unwrap();
+
+        let reader = MmapReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap();
+        let mut records = reader.records();
+
+        assert!(records.next().unwrap().is_ok());
+        assert!(matches!(records.next(), Some(Err(StorageError::Corrupted { position })) if position == second));
+        assert!(records.next().is_none());
+    }
+
+    #[test]
+    fn refresh_should_map_the_file_a_compaction_renamed_over() {
+        let file_name = new_test_file("refresh_should_map_the_file_a_compaction_renamed_over");
+        let mut writer = DiskWriter::new(&file_name, 128, DurabilityMode::EveryWrite).unwrap();
+        let positions = add_numbered_records(&mut writer, 0..20);
+        for position in &positions[..10] {
+            writer.delete_record(*position).unwrap();
+        }
+
+        let options = DiskReaderOptions { skip_deleted: true, ..DiskReaderOptions::unlocked() };
+        let mut reader = MmapReader::new(&file_name, options).unwrap();
+        writer.compact().unwrap();
+        // the old file is still mapped as it was
+        assert_eq!(10, reader.records().count());
+        assert_eq!(b"Record number 10!".to_vec(), reader.read_record_at(positions[10]).unwrap().content.as_ref());
+
+        assert!(reader.refresh().unwrap());
+        let contents: Vec<Vec<u8>> = reader.records().map(|r| r.unwrap().content.to_vec()).collect();
+        assert_eq!((10..20).map(|i| format!("Record number {}!", i).into_bytes()).collect::<Vec<_>>(), contents);
+        assert_eq!(RecordsFileMeta::size() as u64, reader.records().next().unwrap().unwrap().position);
+        assert!(!reader.refresh().unwrap());
+    }
+
+}
@@ -1,4 +1,18 @@
 ﻿
 pub mod disk_writer;
 pub mod disk_reader;
-
+pub mod compaction;
+pub mod recovery;
+pub mod durability;
+pub mod error;
+pub mod segmented;
+pub mod compression;
+pub mod encryption;
+pub mod overflow;
+pub mod free_space;
+pub mod mmap_reader;
+pub mod shared;
+pub mod locking;
+pub mod follow;
+pub mod sparse_index;
+pub mod upgrade;
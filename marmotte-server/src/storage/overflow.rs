@@ -0,0 +1,244 @@
+use std::cmp::min;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use crate::storage::disk_reader::{DiskReader, DiskReaderOptions};
+use crate::storage::disk_writer::{DiskWriter, Record};
+use crate::storage::error::StorageError;
+
+/// Record flag set on every chunk of a record split in chunks, except the last one.
+pub const RECORD_FLAG_MORE_CHUNKS: u8 = 0x20;
+/// Record flag set on every chunk of a record split in chunks, except the first one.
+pub const RECORD_FLAG_CONTINUATION: u8 = 0x40;
+/// Default `DiskWriter::max_chunk_size`, well below the default `DiskReaderOptions::max_record_size`.
+pub const DEFAULT_MAX_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+impl DiskWriter {
+
+    /// Encodes `content` as the records appended at `position`: the record itself, or when it is larger
+    /// than `max_chunk_size`, chunks written one after the other. Each chunk is compressed and encrypted on its own,
+    /// so a reader never needs more than one chunk in memory.
+    /// The first chunk carries `lsn` and `timestamp`, unless `lsn` is 0.
+    pub(crate) fn encode_chunks(&self, position: u64, content: &[u8], lsn: u64, timestamp: u64) -> Result<Vec<Record>, StorageError> {
+        let metadata = if lsn == 0 { None } else { Some((lsn, timestamp)) };
+
+        let chunk_size = self.max_chunk_size.max(1) as usize;
+        if content.len() <= chunk_size {
+            return Ok(vec![Record::encode(position, content, self.compression, self.encryption_key.as_ref(), 0, metadata)?]);
+        }
+
+        let pieces: Vec<&[u8]> = content.chunks(chunk_size).collect();
+        let last = pieces.len() - 1;
+        let mut position = position;
+        let mut chunks = Vec::with_capacity(pieces.len());
+
+        for (i, piece) in pieces.into_iter().enumerate() {
+            let mut flags = 0;
+            if i > 0 {
+                flags |= RECORD_FLAG_CONTINUATION;
+            }
+            if i < last {
+                flags |= RECORD_FLAG_MORE_CHUNKS;
+            }
+            let chunk = Record::encode(position, piece, self.compression, self.encryption_key.as_ref(), flags, metadata.filter(|_| i == 0))?;
+            position += chunk.size();
+            chunks.push(chunk);
+        }
+
+        Ok(chunks)
+    }
+
+    /// Size `content` takes once added: its chunks as encoded by `encode_chunks`, with their LSN and timestamp.
+    pub(crate) fn encoded_size(&self, content: &[u8]) -> Result<u64, StorageError> {
+        let chunks = self.encode_chunks(self.meta.get().position, content, 1, 0)?;
+        Ok(chunks.iter().map(Record::size).sum())
+    }
+
+}
+
+impl DiskReader {
+
+    /// Appends the content of the chunks following `record` to it, when it is the first chunk of a split record.
+    /// The cursor is left after the last chunk.
+    pub(crate) fn read_remaining_chunks(&mut self, mut record: Record) -> Result<Record, StorageError> {
+        let end = self.meta.get().position;
+        let mut position = record.position + record.size();
+        let mut more = record.flags & RECORD_FLAG_MORE_CHUNKS != 0;
+
+        while more {
+            let chunk = read_continuation(&self.file, position, end, &self.options)?;
+            position += chunk.size();
+            more = chunk.flags & RECORD_FLAG_MORE_CHUNKS != 0;
+            record.content.extend_from_slice(&chunk.content);
+        }
+
+        self.seek_to(position)?;
+        Ok(record)
+    }
+
+    /// Opens the record at `position` for streaming: its chunks are read one at a time, as the content is consumed.
+    pub fn open_record(&self, position: u64) -> Result<RecordStream, StorageError> {
+        let file = File::open(&self.file_name)?;
+        let end = self.meta.get().position;
+
+        let first = DiskReader::read_chunk(&file, position, end, &self.options)?;
+        if first.flags & RECORD_FLAG_CONTINUATION != 0 {
+            return Err(StorageError::InvalidPosition(position));
+        }
+
+        Ok(RecordStream {
+            file,
+            options: self.options,
+            end,
+            next_chunk: (first.flags & RECORD_FLAG_MORE_CHUNKS != 0).then(|| position + first.size()),
+            chunk: first.content,
+            offset: 0
+        })
+    }
+
+}
+
+/// Reads the chunk at `position`, which must follow a chunk flagged with `RECORD_FLAG_MORE_CHUNKS`.
+fn read_continuation(file: &File, position: u64, end: u64, options: &DiskReaderOptions) -> Result<Record, StorageError> {
+    let chunk = DiskReader::read_chunk(file, position, end, options).map_err(|e| match e {
+        StorageError::InvalidPosition(_) => StorageError::Corrupted { position },
+        e => e
+    })?;
+
+    if chunk.flags & RECORD_FLAG_CONTINUATION == 0 {
+        return Err(StorageError::Corrupted { position });
+    }
+    Ok(chunk)
+}
+
+/// Content of a record read chunk by chunk, returned by `DiskReader::open_record`.
+/// It has its own handle on the file, the cursor of the reader it comes from does not move.
+pub struct RecordStream {
+    file: File,
+    options: DiskReaderOptions,
+    end: u64,
+    next_chunk: Option<u64>,
+    chunk: Vec<u8>,
+    offset: usize
+}
+
+impl Read for RecordStream {
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.offset == self.chunk.len() {
+            let position = match self.next_chunk {
+                Some(position) => position,
+                None => return Ok(0)
+            };
+            let chunk = read_continuation(&self.file, position, self.end, &self.options).map_err(|e| match e {
+                StorageError::Io(e) => e,
+                e => io::Error::new(io::ErrorKind::InvalidData, e)
+            })?;
+
+            self.next_chunk = (chunk.flags & RECORD_FLAG_MORE_CHUNKS != 0).then(|| position + chunk.size());
+            self.chunk = chunk.content;
+            self.offset = 0;
+        }
+
+        let n = min(buf.len(), self.chunk.len() - self.offset);
+        buf[..n].copy_from_slice(&self.chunk[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, SeekFrom, Write};
+    use super::*;
+    use crate::storage::compression::Compression;
+    use crate::storage::disk_writer::tests::{new_test_file, read_contents};
+    use crate::storage::durability::DurabilityMode;
+    use crate::storage::encryption::EncryptionKey;
+
+    fn attachment(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn record_larger_than_max_chunk_size_should_be_split_and_reassembled() {
+        let file_name = new_test_file("record_larger_than_max_chunk_size_should_be_split_and_reassembled");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.max_chunk_size = 100;
+
+        writer.add_record(b"before").unwrap();
+        let position = writer.add_record(&attachment(1000)).unwrap();
+        writer.bulk_add_records(vec![&attachment(250), b"after"]).unwrap();
+        assert_eq!(4, writer.meta.get().records_count);
+
+        let options = DiskReaderOptions { max_record_size: 200, ..DiskReaderOptions::unlocked() };
+        let mut reader = DiskReader::new(&file_name, options).unwrap();
+        assert_eq!(attachment(1000), reader.read_record_at(position).unwrap().content);
+
+        let contents: Vec<Vec<u8>> = reader.map(|r| r.unwrap().content).collect();
+        assert_eq!(vec![b"before".to_vec(), attachment(1000), attachment(250), b"after".to_vec()], contents);
+    }
+
+    #[test]
+    fn open_record_should_stream_chunks() {
+        let file_name = new_test_file("open_record_should_stream_chunks");
+        let key = EncryptionKey::new(1, [9; 32]);
+        let mut writer = DiskWriter::new_with_key(&file_name, 2048, DurabilityMode::EveryWrite, Some(key)).unwrap();
+        writer.compression = Compression::Lz4;
+        writer.max_chunk_size = 64;
+        let position = writer.add_record(&attachment(1000)).unwrap();
+
+        let options = DiskReaderOptions { encryption_key: Some(key), ..DiskReaderOptions::unlocked() };
+        let reader = DiskReader::new(&file_name, options).unwrap();
+        let mut stream = reader.open_record(position).unwrap();
+        let mut first = [0; 10];
+        stream.read_exact(&mut first).unwrap();
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+
+        assert_eq!(attachment(1000)[..10], first);
+        assert_eq!(attachment(1000)[10..], rest);
+        assert!(reader.open_record(position + 64 + 24 + 16 + 13).is_err());
+    }
+
+    #[test]
+    fn recover_should_drop_record_with_missing_chunks() {
+        let file_name = new_test_file("recover_should_drop_record_with_missing_chunks");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.max_chunk_size = 100;
+        writer.add_record(b"committed").unwrap();
+        let committed = writer.meta.get();
+
+        // crash before the last chunk and the metadata commit reached the disk
+        let chunks = writer.encode_chunks(committed.position, &attachment(1000), committed.last_lsn + 1, Record::now_timestamp()).unwrap();
+        (&writer.file).seek(SeekFrom::Start(committed.position)).unwrap();
+        for chunk in &chunks[..chunks.len() - 1] {
+            (&writer.file).write_all(&chunk.to_bytes()).unwrap();
+        }
+        drop(writer);
+
+        let writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+
+        assert_eq!(committed.position, writer.meta.get().position);
+        assert_eq!(1, writer.meta.get().records_count);
+        assert_eq!(committed.position, writer.file.metadata().unwrap().len());
+    }
+
+    #[test]
+    fn delete_should_only_accept_first_chunk() {
+        let file_name = new_test_file("delete_should_only_accept_first_chunk");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.max_chunk_size = 100;
+        let position = writer.add_record(&attachment(300)).unwrap();
+        writer.add_record(b"kept").unwrap();
+
+        assert!(writer.delete_record(position + 100 + 13).is_err());
+        writer.delete_record(position).unwrap();
+
+        let options = DiskReaderOptions { skip_deleted: true, ..DiskReaderOptions::unlocked() };
+        let contents = read_contents(&file_name, options);
+        assert_eq!(vec![b"kept".to_vec()], contents);
+    }
+
+}
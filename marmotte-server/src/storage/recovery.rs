@@ -0,0 +1,157 @@
+use std::io::{Seek, SeekFrom};
+use crate::storage::disk_writer::{DiskWriter, Record, RecordsFileMeta};
+use crate::storage::error::StorageError;
+use crate::storage::overflow::{RECORD_FLAG_CONTINUATION, RECORD_FLAG_MORE_CHUNKS};
+
+pub struct RecoveryReport {
+    /// Valid records found after the committed position, now part of the file.
+    pub records_recovered: u64,
+    /// Bytes removed after the last valid record.
+    pub bytes_truncated: u64
+}
+
+impl DiskWriter {
+
+    /// Checks every record of the file and repairs what a crash between a record write and
+    /// the following metadata commit can leave behind.
+    ///
+    /// Records before the committed position must all be valid, otherwise the file is reported as corrupted.
+    /// Valid records found after it are committed, and anything following the last valid record is truncated.
+    /// A record split in chunks is only kept when all of its chunks are valid.
+    pub fn recover(&mut self) -> Result<RecoveryReport, StorageError> {
+        let meta = self.meta.get();
+        let file_len = self.file.metadata()?.len();
+        let min_record_size = Record::deleted_flag_offset(0) + 1;
+
+        // end of the last complete record, and of the last chunk read
+        let mut position = RecordsFileMeta::size() as u64;
+        let mut scan_position = position;
+        let mut records_count = 0;
+        let mut records_recovered = 0;
+        // LSNs of the complete records, and of the record whose chunks are being read
+        let mut last_lsn = meta.last_lsn;
+        let mut chain_lsn = 0;
+
+        (&self.file).seek(SeekFrom::Start(position))?;
+
+        while scan_position + min_record_size <= file_len {
+            let max_record_size = file_len - scan_position - min_record_size;
+
+            match Record::read_from(&self.file, max_record_size) {
+                Ok(record) => {
+                    scan_position += record.size();
+                    if record.flags & RECORD_FLAG_CONTINUATION == 0 {
+                        chain_lsn = record.lsn;
+                    }
+                    if record.flags & RECORD_FLAG_MORE_CHUNKS != 0 {
+                        continue;
+                    }
+                    last_lsn = last_lsn.max(chain_lsn);
+                    position = scan_position;
+                    records_count += 1;
+                    if position > meta.position {
+                        records_recovered += 1;
+                    }
+                },
+                Err(StorageError::Io(e)) => return Err(StorageError::Io(e)),
+                Err(_) if scan_position < meta.position => return Err(StorageError::Corrupted { position: scan_position }),
+                Err(_) => break
+            }
+        }
+
+        if position < meta.position {
+            // the committed records go past the end of the file
+            return Err(StorageError::Corrupted { position });
+        }
+
+        if position != meta.position || records_count != meta.records_count || last_lsn != meta.last_lsn {
+            self.sync_and_commit(RecordsFileMeta { position, records_count, last_lsn, ..meta })?;
+        }
+
+        let bytes_truncated = file_len - position;
+        if bytes_truncated > 0 {
+            self.file.set_len(position)?;
+            self.fsync()?;
+        }
+
+        Ok(RecoveryReport { records_recovered, bytes_truncated })
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use super::*;
+    use crate::storage::disk_reader::DiskReaderOptions;
+    use crate::storage::disk_writer::tests::{new_test_file, read_contents};
+    use crate::storage::durability::DurabilityMode;
+
+    #[test]
+    fn recover_should_roll_meta_forward_to_records_written_after_last_commit() {
+        let file_name = new_test_file("recover_should_roll_meta_forward_to_records_written_after_last_commit");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.add_record(b"committed").unwrap();
+        let committed = writer.meta.get();
+
+        // crash after the records were written but before the metadata commit
+        let first = Record::new(committed.position, b"first");
+        let second = Record::new(committed.position + first.size(), b"second");
+        (&writer.file).seek(SeekFrom::Start(committed.position)).unwrap();
+        (&writer.file).write_all(&first.to_bytes()).unwrap();
+        (&writer.file).write_all(&second.to_bytes()).unwrap();
+        drop(writer);
+
+        let writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        let meta = writer.meta.get();
+
+        assert_eq!(3, meta.records_count);
+        assert_eq!(second.position + second.size(), meta.position);
+        assert_eq!(meta.position, writer.file.metadata().unwrap().len());
+        assert_eq!(vec![b"committed".to_vec(), b"first".to_vec(), b"second".to_vec()], read_contents(&file_name, DiskReaderOptions::unlocked()));
+    }
+
+    #[test]
+    fn recover_should_truncate_torn_record() {
+        let file_name = new_test_file("recover_should_truncate_torn_record");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        writer.add_record(b"committed").unwrap();
+        let committed = writer.meta.get();
+
+        let torn = Record::new(committed.position, b"only half of this record reached the disk").to_bytes();
+        (&writer.file).seek(SeekFrom::Start(committed.position)).unwrap();
+        (&writer.file).write_all(&torn[..torn.len() / 2]).unwrap();
+        let torn_len = writer.file.metadata().unwrap().len();
+        drop(writer);
+
+        // opening the file recovers it
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        let report = writer.recovery.take().unwrap();
+
+        assert_eq!(0, report.records_recovered);
+        assert_eq!(torn_len - committed.position, report.bytes_truncated);
+        assert!(report.bytes_truncated > 0);
+        assert_eq!(committed.position, writer.meta.get().position);
+        assert_eq!(1, writer.meta.get().records_count);
+        assert_eq!(committed.position, writer.file.metadata().unwrap().len());
+        assert!(writer.file.metadata().unwrap().len() < torn_len);
+        assert_eq!(0, writer.recover().unwrap().bytes_truncated);
+
+        writer.add_record(b"next").unwrap();
+        assert_eq!(vec![b"committed".to_vec(), b"next".to_vec()], read_contents(&file_name, DiskReaderOptions::unlocked()));
+    }
+
+    #[test]
+    fn recover_should_fail_on_corrupted_committed_record() {
+        let file_name = new_test_file("recover_should_fail_on_corrupted_committed_record");
+        let mut writer = DiskWriter::new(&file_name, 2048, DurabilityMode::EveryWrite).unwrap();
+        let position = writer.add_record(b"committed").unwrap();
+        writer.add_record(b"another one").unwrap();
+
+        (&writer.file).seek(SeekFrom::Start(position + 14)).unwrap();
+        (&writer.file).write_all(b"X").unwrap();
+
+        assert!(writer.recover().is_err());
+    }
+
+}
@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::path::Path;
+use crate::storage::disk_reader::{DiskReader, DiskReaderOptions};
+use crate::storage::disk_writer::{DiskWriter, Record};
+use crate::storage::durability::DurabilityMode;
+use crate::storage::encryption::EncryptionKey;
+use crate::storage::error::StorageError;
+
+/// Location of a record in a segmented collection: the segment number and the record position in that segment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SegmentPosition {
+    pub segment: u32,
+    pub offset: u64
+}
+
+/// Segments of the collection `name` are the files `name.000001.data`, `name.000002.data`, ...
+pub fn segment_file_name(name: &str, segment: u32) -> String {
+    format!("{}.{:06}.data", name, segment)
+}
+
+/// Returns the numbers of the existing segments of the collection `name`, in ascending order.
+pub fn list_segments(name: &str) -> Result<Vec<u32>, StorageError> {
+    let path = Path::new(name);
+    let folder = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new(".")
+    };
+    let prefix = match path.file_name() {
+        Some(n) => format!("{}.", n.to_string_lossy()),
+        None => return Ok(Vec::new())
+    };
+
+    if !folder.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut segments: Vec<u32> = std::fs::read_dir(folder)?
+        .filter_map(|r| r.ok())
+        .filter_map(|e| {
+            let file_name = e.file_name().to_string_lossy().to_string();
+            let number = file_name.strip_prefix(&prefix)?.strip_suffix(".data")?;
+            if number.len() == 6 { number.parse::<u32>().ok() } else { None }
+        })
+        .collect();
+    segments.sort();
+
+    Ok(segments)
+}
+
+/// Appends records to a collection split into segment files.
+/// Writing rolls over to a new segment once the current one reached `max_segment_size`.
+/// Every segment is opened with the durability mode and the key of the collection.
+pub struct SegmentedWriter {
+    pub name: String,
+    pub max_segment_size: u64,
+    pub page_size: u64,
+    pub durability: DurabilityMode,
+    encryption_key: Option<EncryptionKey>,
+    pub segment: u32,
+    pub writer: DiskWriter,
+    /// Writers of the previous segments a record was deleted from, kept open for the next deletions.
+    previous_writers: HashMap<u32, DiskWriter>
+}
+
+impl SegmentedWriter {
+
+    /// Opens the collection `name`, appending to its last segment, or creates its first segment.
+    pub fn new(name: &str, max_segment_size: u64, page_size: u64, durability: DurabilityMode) -> Result<SegmentedWriter, StorageError> {
+        SegmentedWriter::new_with_key(name, max_segment_size, page_size, durability, None)
+    }
+
+    /// Opens the collection `name` like `new`, with the key its segments are encrypted with, see `DiskWriter::new_with_key`.
+    pub fn new_with_key(name: &str, max_segment_size: u64, page_size: u64, durability: DurabilityMode, key: Option<EncryptionKey>) -> Result<SegmentedWriter, StorageError> {
+        let segment = list_segments(name)?.last().copied().unwrap_or(1);
+        let writer = DiskWriter::new_with_key(&segment_file_name(name, segment), page_size, durability, key)?;
+
+        Ok(SegmentedWriter {
+            name: String::from(name),
+            max_segment_size,
+            page_size,
+            durability,
+            encryption_key: key,
+            segment,
+            writer,
+            previous_writers: HashMap::new()
+        })
+    }
+
+    /// Returns true when records taking `size` bytes do not fit in the current segment,
+    /// after `pending` bytes of records not written yet.
+    /// An empty segment always accepts a record, so records larger than a segment are still stored.
+    fn needs_rotation(&self, pending: u64, size: u64) -> bool {
+        let meta = self.writer.meta.get();
+        (meta.records_count > 0 || pending > 0) && meta.position + pending + size > self.max_segment_size
+    }
+
+    /// Closes the current segment and starts writing in the next one, with the same compression and chunk size.
+    pub fn rotate(&mut self) -> Result<(), StorageError> {
+        self.writer.flush()?;
+        let segment = self.segment + 1;
+        let last_lsn = self.writer.meta.get().last_lsn;
+        let (compression, max_chunk_size) = (self.writer.compression, self.writer.max_chunk_size);
+        self.writer = DiskWriter::new_with_key(&segment_file_name(&self.name, segment), self.page_size, self.durability, self.encryption_key)?;
+        self.writer.compression = compression;
+        self.writer.max_chunk_size = max_chunk_size;
+        // LSNs keep increasing across the segments of the collection
+        self.writer.raise_last_lsn(last_lsn)?;
+        self.segment = segment;
+        Ok(())
+    }
+
+    pub fn add_record(&mut self, buf: &[u8]) -> Result<SegmentPosition, StorageError> {
+        if self.needs_rotation(0, self.writer.encoded_size(buf)?) {
+            self.rotate()?;
+        }
+        let offset = self.writer.add_record(buf)?;
+        Ok(SegmentPosition { segment: self.segment, offset })
+    }
+
+    /// Writes the records with one `bulk_add_records` per segment they end up in.
+    pub fn bulk_add_records(&mut self, buffers: Vec<&[u8]>) -> Result<(), StorageError> {
+        let mut batch: Vec<&[u8]> = Vec::new();
+        let mut batch_size = 0;
+
+        for buf in buffers {
+            let size = self.writer.encoded_size(buf)?;
+            if self.needs_rotation(batch_size, size) {
+                if !batch.is_empty() {
+                    self.writer.bulk_add_records(std::mem::take(&mut batch))?;
+                    batch_size = 0;
+                }
+                if self.needs_rotation(0, size) {
+                    self.rotate()?;
+                }
+            }
+            batch.push(buf);
+            batch_size += size;
+        }
+
+        if !batch.is_empty() {
+            self.writer.bulk_add_records(batch)?;
+        }
+        Ok(())
+    }
+
+    pub fn delete_record(&mut self, position: SegmentPosition) -> Result<(), StorageError> {
+        if position.segment == self.segment {
+            return self.writer.delete_record(position.offset);
+        }
+        if position.segment > self.segment {
+            return Err(StorageError::InvalidSegment(position.segment));
+        }
+
+        if !self.previous_writers.contains_key(&position.segment) {
+            let file_name = segment_file_name(&self.name, position.segment);
+            if !Path::new(&file_name).exists() {
+                return Err(StorageError::InvalidSegment(position.segment));
+            }
+            let writer = DiskWriter::new_with_key(&file_name, self.page_size, self.durability, self.encryption_key)?;
+            self.previous_writers.insert(position.segment, writer);
+        }
+        self.previous_writers.get_mut(&position.segment).unwrap().delete_record(position.offset)
+    }
+
+    /// Deletes a whole segment file. The segment being written cannot be removed.
+    pub fn remove_segment(&mut self, segment: u32) -> Result<(), StorageError> {
+        if segment >= self.segment {
+            return Err(StorageError::InvalidSegment(segment));
+        }
+        self.previous_writers.remove(&segment);
+        std::fs::remove_file(segment_file_name(&self.name, segment))?;
+        Ok(())
+    }
+
+}
+
+/// Reads the records of all the segments of a collection, in order.
+pub struct SegmentedReader {
+    pub name: String,
+    pub segments: Vec<u32>,
+    pub current: Option<(u32, DiskReader)>,
+    options: DiskReaderOptions,
+    next_segment: usize
+}
+
+impl SegmentedReader {
+
+    pub fn new(name: &str, options: DiskReaderOptions) -> Result<SegmentedReader, StorageError> {
+        Ok(SegmentedReader {
+            name: String::from(name),
+            segments: list_segments(name)?,
+            current: None,
+            options,
+            next_segment: 0
+        })
+    }
+
+    fn open_segment(&self, segment: u32) -> Result<DiskReader, StorageError> {
+        DiskReader::new(&segment_file_name(&self.name, segment), self.options)
+    }
+
+    pub fn read_record_at(&mut self, position: SegmentPosition) -> Result<Record, StorageError> {
+        match &mut self.current {
+            Some((segment, reader)) if *segment == position.segment => reader.read_record_at(position.offset),
+            _ => self.open_segment(position.segment)?.read_record_at(position.offset)
+        }
+    }
+
+}
+
+impl Iterator for SegmentedReader {
+
+    type Item = Result<(SegmentPosition, Box<Record>), StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((segment, reader)) = &mut self.current {
+                match reader.next() {
+                    Some(Ok(record)) => {
+                        let position = SegmentPosition { segment: *segment, offset: record.position };
+                        return Some(Ok((position, record)));
+                    },
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => self.current = None
+                }
+            }
+
+            let segment = *self.segments.get(self.next_segment)?;
+            self.next_segment += 1;
+            match self.open_segment(segment) {
+                Ok(reader) => self.current = Some((segment, reader)),
+                Err(e) => return Some(Err(e))
+            }
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_test_collection(name: &str) -> String {
+        let folder = format!("test_folder/segments/{}", name);
+        if std::fs::exists(&folder).unwrap() {
+            std::fs::remove_dir_all(&folder).unwrap();
+        }
+        std::fs::create_dir_all(&folder).unwrap();
+        format!("{}/collection", folder)
+    }
+
+    #[test]
+    fn writer_should_roll_over_to_next_segment_when_full() {
+        let name = new_test_collection("writer_should_roll_over_to_next_segment_when_full");
+        let mut writer = SegmentedWriter::new(&name, 1024, 512, DurabilityMode::EveryWrite).unwrap();
+
+        let positions: Vec<SegmentPosition> = (0..100)
+            .map(|i| writer.add_record(format!("Record number {}!", i).as_bytes()).unwrap())
+            .collect();
+
+        let segments = list_segments(&name).unwrap();
+        assert!(segments.len() > 1);
+        assert_eq!(1, positions[0].segment);
+        assert_eq!(*segments.last().unwrap(), positions[99].segment);
+        assert!(Path::new(&format!("{}.000002.data", name)).exists());
+        for segment in segments {
+            let reader = DiskReader::new(&segment_file_name(&name, segment), DiskReaderOptions::unlocked()).unwrap();
+            assert!(reader.meta.get().position <= 1024);
+        }
+    }
+
+    #[test]
+    fn reader_should_iterate_all_segments_in_order() {
+        let name = new_test_collection("reader_should_iterate_all_segments_in_order");
+        let mut writer = SegmentedWriter::new(&name, 1024, 512, DurabilityMode::EveryWrite).unwrap();
+        let values: Vec<Vec<u8>> = (0..100).map(|i| format!("Record number {}!", i).into_bytes()).collect();
+        writer.bulk_add_records(values.iter().map(|v| v.as_slice()).collect()).unwrap();
+        let position = writer.add_record(b"last one").unwrap();
+        drop(writer);
+
+        let records: Vec<(SegmentPosition, Box<Record>)> = SegmentedReader::new(&name, DiskReaderOptions::unlocked()).unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+
+        assert!(list_segments(&name).unwrap().len() > 1);
+        assert_eq!(101, records.len());
+        assert_eq!(values, records[..100].iter().map(|(_, r)| r.content.clone()).collect::<Vec<Vec<u8>>>());
+        assert_eq!(position, records[100].0);
+    }
+
+    #[test]
+    fn reopened_writer_should_append_to_last_segment() {
+        let name = new_test_collection("reopened_writer_should_append_to_last_segment");
+        let mut writer = SegmentedWriter::new(&name, 1024, 512, DurabilityMode::EveryWrite).unwrap();
+        for i in 0..100 {
+            writer.add_record(format!("Record number {}!", i).as_bytes()).unwrap();
+        }
+        let last_segment = writer.segment;
+        drop(writer);
+
+        let mut writer = SegmentedWriter::new(&name, 1024, 512, DurabilityMode::EveryWrite).unwrap();
+        let position = writer.add_record(b"appended").unwrap();
+
+        assert!(position.segment >= last_segment);
+        let mut reader = SegmentedReader::new(&name, DiskReaderOptions::unlocked()).unwrap();
+        assert_eq!(b"appended".to_vec(), reader.read_record_at(position).unwrap().content);
+    }
+
+    #[test]
+    fn delete_record_of_previous_segment_should_be_skipped_by_reader() {
+        let name = new_test_collection("delete_record_of_previous_segment_should_be_skipped_by_reader");
+        let mut writer = SegmentedWriter::new(&name, 1024, 512, DurabilityMode::EveryWrite).unwrap();
+        let positions: Vec<SegmentPosition> = (0..100)
+            .map(|i| writer.add_record(format!("Record number {}!", i).as_bytes()).unwrap())
+            .collect();
+
+        assert!(positions[99].segment > 2);
+        writer.delete_record(positions[0]).unwrap();
+        writer.remove_segment(positions[99].segment - 1).unwrap();
+
+        let options = DiskReaderOptions { skip_deleted: true, ..DiskReaderOptions::unlocked() };
+        let contents: Vec<Vec<u8>> = SegmentedReader::new(&name, options).unwrap().map(|r| r.unwrap().1.content).collect();
+
+        assert!(!contents.contains(&b"Record number 0!".to_vec()));
+        assert!(contents.contains(&b"Record number 1!".to_vec()));
+        assert!(contents.contains(&b"Record number 99!".to_vec()));
+        assert!(writer.remove_segment(writer.segment).is_err());
+    }
+
+    #[test]
+    fn segments_should_hold_records_split_in_chunks() {
+        let name = new_test_collection("segments_should_hold_records_split_in_chunks");
+        let mut writer = SegmentedWriter::new(&name, 1024, 512, DurabilityMode::EveryWrite).unwrap();
+        writer.writer.max_chunk_size = 16;
+
+        let values: Vec<Vec<u8>> = (0..40).map(|i| format!("Record number {} split in several chunks", i).into_bytes()).collect();
+        for value in &values[..20] {
+            writer.add_record(value).unwrap();
+        }
+        writer.bulk_add_records(values[20..].iter().map(|v| v.as_slice()).collect()).unwrap();
+        assert_eq!(16, writer.writer.max_chunk_size);
+
+        let segments = list_segments(&name).unwrap();
+        assert!(segments.len() > 2);
+        for segment in segments {
+            let reader = DiskReader::new(&segment_file_name(&name, segment), DiskReaderOptions::unlocked()).unwrap();
+            assert!(reader.meta.get().position <= 1024, "segment {}", segment);
+        }
+        let contents: Vec<Vec<u8>> = SegmentedReader::new(&name, DiskReaderOptions::unlocked()).unwrap().map(|r| r.unwrap().1.content).collect();
+        assert_eq!(values, contents);
+    }
+
+    #[test]
+    fn deletes_in_previous_segment_should_reuse_its_writer() {
+        let name = new_test_collection("deletes_in_previous_segment_should_reuse_its_writer");
+        let mut writer = SegmentedWriter::new(&name, 1024, 512, DurabilityMode::EveryWrite).unwrap();
+        let positions: Vec<SegmentPosition> = (0..100)
+            .map(|i| writer.add_record(format!("Record number {}!", i).as_bytes()).unwrap())
+            .collect();
+
+        writer.delete_record(positions[0]).unwrap();
+        writer.delete_record(positions[1]).unwrap();
+        assert_eq!(1, writer.previous_writers.len());
+
+        assert!(matches!(writer.delete_record(SegmentPosition { segment: writer.segment + 1, offset: positions[0].offset }), Err(StorageError::InvalidSegment(_))));
+        writer.remove_segment(1).unwrap();
+        assert!(writer.previous_writers.is_empty());
+        assert!(matches!(writer.delete_record(positions[2]), Err(StorageError::InvalidSegment(1))));
+    }
+
+    #[test]
+    fn every_segment_should_be_written_with_the_durability_and_key_of_the_collection() {
+        let name = new_test_collection("every_segment_should_be_written_with_the_durability_and_key_of_the_collection");
+        let key = EncryptionKey::new(7, [42; 32]);
+        let mut writer = SegmentedWriter::new_with_key(&name, 1024, 512, DurabilityMode::OsBuffered, Some(key)).unwrap();
+        let positions: Vec<SegmentPosition> = (0..100)
+            .map(|i| writer.add_record(format!("Record number {}!", i).as_bytes()).unwrap())
+            .collect();
+        assert!(positions[99].segment > 1);
+
+        writer.delete_record(positions[0]).unwrap();
+        assert_eq!(DurabilityMode::OsBuffered, writer.previous_writers[&1].durability);
+        assert_eq!(DurabilityMode::OsBuffered, writer.writer.durability);
+        drop(writer);
+        assert!(matches!(SegmentedWriter::new(&name, 1024, 512, DurabilityMode::OsBuffered), Err(StorageError::MissingKey)));
+
+        let options = DiskReaderOptions { skip_deleted: true, encryption_key: Some(key), ..DiskReaderOptions::unlocked() };
+        let contents: Vec<Vec<u8>> = SegmentedReader::new(&name, options).unwrap().map(|r| r.unwrap().1.content).collect();
+        assert_eq!((1..100).map(|i| format!("Record number {}!", i).into_bytes()).collect::<Vec<_>>(), contents);
+    }
+
+}
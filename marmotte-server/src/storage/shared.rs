@@ -0,0 +1,150 @@
+use std::sync::Mutex;
+use crate::storage::disk_reader::{DiskReader, DiskReaderOptions};
+use crate::storage::disk_writer::{DiskWriter, RecordsFileMeta};
+use crate::storage::error::StorageError;
+use crate::storage::locking::FileLock;
+
+/// Data file shared between one writer and any number of readers of the same process.
+///
+/// Readers are snapshots: each one is pinned to the `position` and `records_count` committed when it was taken,
+/// so it never reads the records the writer is appending meanwhile. A snapshot still sees records deleted after it
+/// was taken flagged as deleted, deleting only rewrites the deleted flag.
+///
+/// The writer never reuses the space of deleted records here, a committed record is never rewritten while a snapshot
+/// may be reading it. Compaction replaces the file and is not available through a shared file.
+pub struct SharedFile {
+    pub file_name: String,
+    writer: Mutex<DiskWriter>,
+    /// Metadata of the last write, what new snapshots see.
+    committed: Mutex<RecordsFileMeta>,
+    /// `DiskWriter::reuse_free_space` of the writer before it was shared, given back by `into_writer`.
+    reuse_free_space: bool
+}
+
+impl SharedFile {
+
+    pub fn new(mut writer: DiskWriter) -> SharedFile {
+        let reuse_free_space = std::mem::replace(&mut writer.reuse_free_space, false);
+        let committed = writer.meta.get();
+
+        SharedFile { file_name: writer.file_name.clone(), writer: Mutex::new(writer), committed: Mutex::new(committed), reuse_free_space }
+    }
+
+    /// Metadata new snapshots are pinned to.
+    pub fn committed(&self) -> RecordsFileMeta {
+        *self.committed.lock().unwrap()
+    }
+
+    /// Returns a reader over the records committed so far. It has its own handle on the file and can be moved to another thread.
+    /// It does not lock the file, whatever `options.lock` says: the writer holds an exclusive lock on it.
+    pub fn snapshot(&self, options: DiskReaderOptions) -> Result<DiskReader, StorageError> {
+        DiskReader::at_snapshot(&self.file_name, self.committed(), DiskReaderOptions { lock: FileLock::None, ..options })
+    }
+
+    /// Runs `f` with the writer, then publishes what it committed to the next snapshots.
+    fn write<T, F>(&self, f: F) -> Result<T, StorageError> where F: FnOnce(&mut DiskWriter) -> Result<T, StorageError> {
+        let mut writer = self.writer.lock().unwrap();
+        let result = f(&mut writer);
+        // the records are written before the metadata pointing to them, even when `f` failed halfway
+        *self.committed.lock().unwrap() = writer.meta.get();
+        result
+    }
+
+    pub fn add_record(&self, buf: &[u8]) -> Result<u64, StorageError> {
+        self.write(|writer| writer.add_record(buf))
+    }
+
+    pub fn delete_record(&self, position: u64) -> Result<(), StorageError> {
+        self.write(|writer| writer.delete_record(position))
+    }
+
+    /// Returns the writer, once no snapshot needs the file to be shared anymore.
+    pub fn into_writer(self) -> DiskWriter {
+        let mut writer = self.writer.into_inner().unwrap();
+        writer.reuse_free_space = self.reuse_free_space;
+        writer
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use super::*;
+    use crate::storage::disk_writer::tests::new_test_file;
+    use crate::storage::durability::DurabilityMode;
+
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn snapshot_should_not_see_records_added_after_it() {
+        let file_name = new_test_file("snapshot_should_not_see_records_added_after_it");
+        let shared = SharedFile::new(DiskWriter::new(&file_name, 256, DurabilityMode::OsBuffered).unwrap());
+        shared.add_record(b"first").unwrap();
+        let second = shared.add_record(b"second").unwrap();
+
+        let mut snapshot = shared.snapshot(DiskReaderOptions::unlocked()).unwrap();
+        shared.add_record(b"third").unwrap();
+        shared.delete_record(second).unwrap();
+
+        assert_eq!(2, snapshot.meta.get().records_count);
+        let records: Vec<_> = snapshot.by_ref().map(|r| r.unwrap()).collect();
+        assert_eq!(vec![b"first".to_vec(), b"second".to_vec()], records.iter().map(|r| r.content.clone()).collect::<Vec<_>>());
+        assert!(records[1].deleted);
+        assert_eq!(3, shared.snapshot(DiskReaderOptions::unlocked()).unwrap().count());
+    }
+
+    #[test]
+    fn shared_file_should_not_reuse_space_of_deleted_records() {
+        let file_name = new_test_file("shared_file_should_not_reuse_space_of_deleted_records");
+        let mut writer = DiskWriter::new(&file_name, 256, DurabilityMode::OsBuffered).unwrap();
+        writer.reuse_free_space = true;
+        let shared = SharedFile::new(writer);
+        let first = shared.add_record(b"some record").unwrap();
+        shared.delete_record(first).unwrap();
+
+        assert!(shared.add_record(b"same record").unwrap() > first);
+        assert!(shared.into_writer().reuse_free_space);
+    }
+
+    #[test]
+    fn snapshots_should_be_read_on_other_threads_while_writing() {
+        assert_send::<Arc<SharedFile>>();
+        assert_send::<DiskReader>();
+
+        let file_name = new_test_file("snapshots_should_be_read_on_other_threads_while_writing");
+        let shared = Arc::new(SharedFile::new(DiskWriter::new(&file_name, 1024, DurabilityMode::OsBuffered).unwrap()));
+
+        let writer = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                for i in 0..500 {
+                    shared.add_record(format!("Record number {}!", i).as_bytes()).unwrap();
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4).map(|_| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                for _ in 0..20 {
+                    let snapshot = shared.snapshot(DiskReaderOptions::unlocked()).unwrap();
+                    let expected = snapshot.meta.get().records_count as usize;
+                    let contents: Vec<Vec<u8>> = snapshot.map(|r| r.unwrap().content).collect();
+
+                    assert_eq!(expected, contents.len());
+                    for (i, content) in contents.iter().enumerate() {
+                        assert_eq!(format!("Record number {}!", i).as_bytes(), content.as_slice());
+                    }
+                }
+            })
+        }).collect();
+
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(500, shared.committed().records_count);
+    }
+
+}
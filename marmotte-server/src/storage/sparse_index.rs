@@ -0,0 +1,284 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, Write};
+use std::path::Path;
+use crate::storage::disk_reader::DiskReader;
+use crate::storage::disk_writer::{Record, RecordsFileMeta};
+use crate::storage::error::StorageError;
+use crate::storage::overflow::RECORD_FLAG_CONTINUATION;
+
+/// The writer records the start of the first record appended at least this many bytes after the previous checkpoint.
+pub const SPARSE_INDEX_INTERVAL: u64 = 64 * 1024;
+
+/// Positions of some records of a data file, about one every `SPARSE_INDEX_INTERVAL` bytes,
+/// persisted next to it in `<file>.idx` as a sequence of big endian u64.
+///
+/// The index is a hint used to read the file backwards: it is appended without syncing, and a checkpoint may no longer
+/// be the start of a record once deleted records were merged in the free-space map. Readers check every checkpoint
+/// they use, a missing or wrong one only means more of the file is scanned.
+pub struct SparseIndex {
+    pub file_name: String,
+    positions: Vec<u64>,
+    file: Option<File>
+}
+
+impl SparseIndex {
+
+    pub fn file_name_of(data_file_name: &str) -> String {
+        format!("{}.idx", data_file_name)
+    }
+
+    pub fn empty(file_name: &str) -> SparseIndex {
+        SparseIndex { file_name: String::from(file_name), positions: Vec::new(), file: None }
+    }
+
+    /// Loads the index, up to its first torn or out of order entry. A missing index is an empty one.
+    pub fn load(file_name: &str) -> Result<SparseIndex, StorageError> {
+        let mut index = SparseIndex::empty(file_name);
+        if !Path::new(file_name).exists() {
+            return Ok(index);
+        }
+
+        for entry in std::fs::read(file_name)?.chunks_exact(8) {
+            let position = u64::from_be_bytes(entry.try_into().unwrap());
+            if index.positions.last().is_some_and(|last| *last >= position) {
+                break;
+            }
+            index.positions.push(position);
+        }
+        Ok(index)
+    }
+
+    pub fn positions(&self) -> &[u64] {
+        &self.positions
+    }
+
+    /// Records `position`, the start of a record just appended, if it is far enough from the previous checkpoint.
+    pub fn add(&mut self, position: u64) -> Result<(), StorageError> {
+        let previous = self.positions.last().copied().unwrap_or(RecordsFileMeta::size() as u64);
+        if position < previous + SPARSE_INDEX_INTERVAL {
+            return Ok(());
+        }
+
+        if self.file.is_none() {
+            self.file = Some(OpenOptions::new().create(true).append(true).open(&self.file_name)?);
+        }
+        if let Some(file) = &mut self.file {
+            file.write_all(&position.to_be_bytes())?;
+        }
+        self.positions.push(position);
+        Ok(())
+    }
+
+    /// Forgets the checkpoints from `end`, after the data file was truncated, and rewrites the index if it changed.
+    pub fn truncate(&mut self, end: u64) -> Result<(), StorageError> {
+        let kept = self.positions.partition_point(|position| *position < end);
+        let entries = if Path::new(&self.file_name).exists() { std::fs::metadata(&self.file_name)?.len() / 8 } else { 0 };
+        if kept == self.positions.len() && entries == kept as u64 {
+            return Ok(());
+        }
+
+        self.positions.truncate(kept);
+        self.file = None;
+        let buf: Vec<u8> = self.positions.iter().flat_map(|position| position.to_be_bytes()).collect();
+        std::fs::write(&self.file_name, buf)?;
+        Ok(())
+    }
+
+}
+
+impl DiskReader {
+
+    /// Turns the reader into an iterator over the committed records, from the last one to the first.
+    /// Only the records after the checkpoint preceding the current block are read, see `SparseIndex`.
+    /// The file order is the commit order as long as the writer does not reuse the space of deleted records,
+    /// this fails with `ReusedSpace` once it did.
+    pub fn backward(self) -> Result<BackwardRecords, StorageError> {
+        self.check_commit_order()?;
+        let index = SparseIndex::load(&SparseIndex::file_name_of(&self.file_name))?;
+        let end = self.meta.get().position;
+        let checkpoints = index.positions.into_iter().filter(|position| *position < end).collect();
+
+        Ok(BackwardRecords { reader: self, checkpoints, end, block: Vec::new() })
+    }
+
+}
+
+/// Records of a data file from the last one to the first, returned by `DiskReader::backward`.
+///
+/// The file is read by blocks, from one checkpoint of the sparse index to the next one, forwards.
+/// The records of a block are then returned in reverse order.
+pub struct BackwardRecords {
+    reader: DiskReader,
+    checkpoints: Vec<u64>,
+    /// Start of the last block read, every record before it is still to be returned.
+    end: u64,
+    block: Vec<Record>
+}
+
+impl BackwardRecords {
+
+    /// Reads the records from `start` to `end`. Returns `None` when they do not end exactly at `end`,
+    /// `start` is then not the start of a record.
+    fn read_block(&mut self, start: u64) -> Result<Option<Vec<Record>>, StorageError> {
+        let mut records = Vec::new();
+        let mut position = start;
+
+        while position < self.end {
+            let record = DiskReader::read_chunk(&self.reader.file, position, self.end, &self.reader.options)?;
+            if record.flags & RECORD_FLAG_CONTINUATION != 0 {
+                return Ok(None);
+            }
+            let record = self.reader.read_remaining_chunks(record)?;
+            position = self.reader.file.stream_position()?;
+            records.push(record);
+        }
+
+        Ok(if position == self.end { Some(records) } else { None })
+    }
+
+    /// Reads the block before `end`, starting from the closest checkpoint that turns out to be the start of a record.
+    fn read_previous_block(&mut self) -> Result<(), StorageError> {
+        loop {
+            let start = self.checkpoints.pop().unwrap_or(RecordsFileMeta::size() as u64);
+            let is_checkpoint = start > RecordsFileMeta::size() as u64;
+
+            match self.read_block(start) {
+                Ok(Some(records)) => {
+                    self.block = records;
+                    self.end = start;
+                    return Ok(());
+                },
+                Ok(None) if !is_checkpoint => return Err(StorageError::Corrupted { position: start }),
+                Err(StorageError::Io(e)) => return Err(StorageError::Io(e)),
+                Err(e) if !is_checkpoint => return Err(e),
+                // not the start of a record anymore, try the previous checkpoint
+                _ => continue
+            }
+        }
+    }
+
+}
+
+impl Iterator for BackwardRecords {
+
+    type Item = Result<Box<Record>, StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.block.pop() {
+                if self.reader.options.skip_deleted && record.deleted {
+                    continue;
+                }
+                return Some(Ok(Box::new(record)));
+            }
+            if self.end <= RecordsFileMeta::size() as u64 {
+                return None;
+            }
+            if let Err(e) = self.read_previous_block() {
+                // as for `DiskReader`, the iteration ends with the error
+                self.end = RecordsFileMeta::size() as u64;
+                return Some(Err(e));
+            }
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::disk_reader::DiskReaderOptions;
+    use crate::storage::disk_writer::DiskWriter;
+    use crate::storage::disk_writer::tests::new_test_file;
+    use crate::storage::durability::DurabilityMode;
+
+    fn record_content(i: usize) -> Vec<u8> {
+        let mut content = format!("Record number {}!", i).into_bytes();
+        content.resize(1000 + i % 7, b'.');
+        content
+    }
+
+    fn backward_contents(file_name: &str, options: DiskReaderOptions) -> Vec<Vec<u8>> {
+        DiskReader::new(file_name, options).unwrap().backward().unwrap().map(|r| r.unwrap().content).collect()
+    }
+
+    #[test]
+    fn backward_iteration_should_return_records_from_last_to_first() {
+        let file_name = new_test_file("backward_iteration_should_return_records_from_last_to_first");
+        let mut writer = DiskWriter::new(&file_name, 4096, DurabilityMode::OsBuffered).unwrap();
+        writer.max_chunk_size = 700;
+        for i in 0..150 {
+            writer.add_record(&record_content(i)).unwrap();
+        }
+        writer.bulk_add_records((150..300).map(record_content).collect::<Vec<_>>().iter().map(|c| c.as_slice()).collect()).unwrap();
+
+        let index = SparseIndex::load(&SparseIndex::file_name_of(&file_name)).unwrap();
+        assert!(index.positions().len() >= 4);
+
+        let expected: Vec<Vec<u8>> = (0..300).rev().map(record_content).collect();
+        assert_eq!(expected, backward_contents(&file_name, DiskReaderOptions::unlocked()));
+
+        let latest: Vec<Vec<u8>> = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap()
+            .backward().unwrap().take(3).map(|r| r.unwrap().content).collect();
+        assert_eq!(expected[..3].to_vec(), latest);
+    }
+
+    #[test]
+    fn backward_iteration_should_skip_stale_checkpoints() {
+        let file_name = new_test_file("backward_iteration_should_skip_stale_checkpoints");
+        let mut writer = DiskWriter::new(&file_name, 4096, DurabilityMode::OsBuffered).unwrap();
+        writer.reuse_free_space = true;
+        let positions: Vec<u64> = (0..200).map(|i| writer.add_record(&record_content(i)).unwrap()).collect();
+        let checkpoint = SparseIndex::load(&SparseIndex::file_name_of(&file_name)).unwrap().positions()[1];
+        let i = positions.iter().position(|p| *p == checkpoint).unwrap();
+
+        // merged with its neighbours in the free-space map, the checkpoint is now in the middle of a deleted record
+        writer.delete_record(positions[i - 1]).unwrap();
+        writer.delete_record(positions[i]).unwrap();
+        assert!(writer.free_space.extents().iter().any(|e| e.position < checkpoint && e.end() > checkpoint));
+
+        let options = DiskReaderOptions { skip_deleted: true, ..DiskReaderOptions::unlocked() };
+        let expected: Vec<Vec<u8>> = (0..200).rev().filter(|j| *j != i && *j != i - 1).map(record_content).collect();
+        assert_eq!(expected, backward_contents(&file_name, options));
+    }
+
+    #[test]
+    fn index_should_be_truncated_with_the_file_and_rebuilt_by_compaction() {
+        let file_name = new_test_file("index_should_be_truncated_with_the_file_and_rebuilt_by_compaction");
+        let mut writer = DiskWriter::new(&file_name, 4096, DurabilityMode::OsBuffered).unwrap();
+        let positions: Vec<u64> = (0..200).map(|i| writer.add_record(&record_content(i)).unwrap()).collect();
+        for position in &positions[..100] {
+            writer.delete_record(*position).unwrap();
+        }
+
+        writer.compact().unwrap();
+        let index = SparseIndex::load(&SparseIndex::file_name_of(&file_name)).unwrap();
+        assert!(index.positions().iter().all(|p| *p < writer.meta.get().position));
+        let expected: Vec<Vec<u8>> = (100..200).rev().map(record_content).collect();
+        assert_eq!(expected, backward_contents(&file_name, DiskReaderOptions::unlocked()));
+
+        let mut index = SparseIndex::load(&SparseIndex::file_name_of(&file_name)).unwrap();
+        let first = index.positions()[0];
+        index.truncate(first).unwrap();
+        assert!(SparseIndex::load(&SparseIndex::file_name_of(&file_name)).unwrap().positions().is_empty());
+    }
+
+    #[test]
+    fn backward_iteration_should_be_refused_once_space_of_deleted_records_is_reused() {
+        let file_name = new_test_file("backward_iteration_should_be_refused_once_space_of_deleted_records_is_reused");
+        let mut writer = DiskWriter::new(&file_name, 4096, DurabilityMode::OsBuffered).unwrap();
+        writer.reuse_free_space = true;
+        let positions: Vec<u64> = (0..100).map(|i| writer.add_record(&record_content(i)).unwrap()).collect();
+        writer.delete_record(positions[0]).unwrap();
+        writer.delete_record(positions[1]).unwrap();
+        assert_eq!(positions[0], writer.add_record(&record_content(100)).unwrap());
+
+        let reader = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap();
+        assert!(matches!(reader.backward(), Err(StorageError::ReusedSpace)));
+
+        writer.compact().unwrap();
+        let expected: Vec<Vec<u8>> = (2..=100).rev().map(record_content).collect();
+        assert_eq!(expected, backward_contents(&file_name, DiskReaderOptions::unlocked()));
+    }
+
+}
@@ -0,0 +1,225 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use crate::storage::compaction::{sync_parent_folder, COPY_BATCH_SIZE};
+use crate::storage::disk_writer::{DiskWriter, RecordsFileMeta, RECORDS_FILE_VERSION};
+use crate::storage::durability::DurabilityMode;
+use crate::storage::error::StorageError;
+use crate::storage::free_space::FreeSpaceMap;
+use crate::storage::locking::{lock_file, FileLock};
+use crate::storage::sparse_index::SparseIndex;
+
+/// Size of the version 1 header: version, records count, position and page size.
+const V1_HEADER_SIZE: u64 = 8 + 8 + 8 + 8;
+
+pub struct UpgradeReport {
+    /// Version of the file before the upgrade.
+    pub from_version: u64,
+    pub records_kept: u64,
+    pub records_removed: u64
+}
+
+impl RecordsFileMeta {
+
+    /// Reads the header of a version 1 file, a single block without checksum with the records right after it.
+    /// Returns `None` if the start of the file does not look like one.
+    pub fn read_v1_header(file: &mut File) -> Result<Option<RecordsFileMeta>, StorageError> {
+        let len = file.metadata()?.len();
+        if len < V1_HEADER_SIZE {
+            return Ok(None);
+        }
+
+        file.seek(SeekFrom::Start(0))?;
+        let mut buf = [0; V1_HEADER_SIZE as usize];
+        file.read_exact(&mut buf)?;
+        let field = |i: usize| u64::from_be_bytes(buf[i * 8..(i + 1) * 8].try_into().unwrap());
+        let (version, records_count, position, page_size) = (field(0), field(1), field(2), field(3));
+
+        if version != 1 || position < V1_HEADER_SIZE || position > len || records_count > position {
+            return Ok(None);
+        }
+        Ok(Some(RecordsFileMeta { version, records_count, position, page_size, ..RecordsFileMeta::empty() }))
+    }
+
+    /// Returns the version of the file, whatever its header layout. Nothing else is checked.
+    pub fn read_version(file: &mut File) -> Result<u64, StorageError> {
+        match RecordsFileMeta::read_latest_slot(file) {
+            Err(StorageError::CorruptedHeader) => RecordsFileMeta::read_v1_header(file)?
+                .map(|meta| meta.version)
+                .ok_or(StorageError::CorruptedHeader),
+            result => result.map(|meta| meta.version)
+        }
+    }
+
+}
+
+/// Reads the record at the current position of `file`, in the layout of version 1:
+/// no flags in the length prefix and a checksum covering the content only.
+/// Returns its content and its deleted flag.
+fn read_outdated_record(file: &mut File, end: u64) -> Result<(Vec<u8>, bool), StorageError> {
+    let position = file.stream_position()?;
+    let mut header = [0; 8 + 4];
+    file.read_exact(&mut header).map_err(|e| StorageError::reading_record(position, e))?;
+    let len = u64::from_be_bytes(header[..8].try_into().unwrap());
+    let checksum = u32::from_be_bytes(header[8..].try_into().unwrap());
+
+    if len > end.saturating_sub(position + 8 + 4 + 1) {
+        return Err(StorageError::Corrupted { position });
+    }
+
+    let mut buf = vec![0; len as usize + 1];
+    file.read_exact(&mut buf).map_err(|e| StorageError::reading_record(position, e))?;
+    let deleted = buf.pop() != Some(0);
+
+    if crc32fast::hash(&buf) != checksum {
+        return Err(StorageError::Corrupted { position });
+    }
+    Ok((buf, deleted))
+}
+
+/// Converts a version 1 file to the current version.
+/// Returns `None` for a file already readable, which is left as is.
+///
+/// The live records are rewritten in the current layout, with new LSNs and the time of the upgrade as commit timestamp,
+/// and the deleted ones are dropped. Like a compaction, the new file is written and synced under a temporary name
+/// before being renamed over the original one, and the positions of the records change.
+pub fn upgrade_file(file_name: &str) -> Result<Option<UpgradeReport>, StorageError> {
+    let mut file = OpenOptions::new().read(true).open(file_name)?;
+    lock_file(&file, file_name, true, FileLock::Fail)?;
+
+    let from_version = RecordsFileMeta::read_version(&mut file)?;
+    if from_version == RECORDS_FILE_VERSION {
+        return Ok(None);
+    }
+    let meta = match RecordsFileMeta::read_v1_header(&mut file)? {
+        Some(meta) if from_version == 1 => meta,
+        _ => return Err(StorageError::UnsupportedVersion(from_version))
+    };
+    let upgrade_file_name = format!("{}.upgrade", file_name);
+    if Path::new(&upgrade_file_name).exists() {
+        // leftover of an upgrade interrupted before the swap
+        std::fs::remove_file(&upgrade_file_name)?;
+    }
+
+    let mut records_kept = 0;
+    let mut records_removed = 0;
+    {
+        let page_size = meta.page_size.max(RecordsFileMeta::size() as u64);
+        let mut target = DiskWriter::new(&upgrade_file_name, page_size, DurabilityMode::OsBuffered)?;
+        let mut batch: Vec<Vec<u8>> = Vec::with_capacity(COPY_BATCH_SIZE);
+
+        file.seek(SeekFrom::Start(V1_HEADER_SIZE))?;
+        while file.stream_position()? < meta.position {
+            let (content, deleted) = read_outdated_record(&mut file, meta.position)?;
+            if deleted {
+                records_removed += 1;
+                continue;
+            }
+            records_kept += 1;
+            batch.push(content);
+
+            if batch.len() == COPY_BATCH_SIZE {
+                target.bulk_add_records(batch.iter().map(|c| c.as_slice()).collect())?;
+                batch.clear();
+            }
+        }
+
+        if !batch.is_empty() {
+            target.bulk_add_records(batch.iter().map(|c| c.as_slice()).collect())?;
+        }
+        target.flush()?;
+    }
+
+    // the original file stays locked until it is replaced
+    std::fs::rename(&upgrade_file_name, file_name)?;
+    sync_parent_folder(file_name);
+    drop(file);
+
+    let index_file_name = SparseIndex::file_name_of(file_name);
+    let upgrade_index_file_name = SparseIndex::file_name_of(&upgrade_file_name);
+    if Path::new(&upgrade_index_file_name).exists() {
+        std::fs::rename(&upgrade_index_file_name, &index_file_name)?;
+    } else if Path::new(&index_file_name).exists() {
+        std::fs::remove_file(&index_file_name)?;
+    }
+    // the new file has no deleted record
+    let free_space_file_name = FreeSpaceMap::file_name_of(file_name);
+    if Path::new(&free_space_file_name).exists() {
+        std::fs::remove_file(&free_space_file_name)?;
+    }
+
+    Ok(Some(UpgradeReport { from_version, records_kept, records_removed }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::disk_reader::{DiskReader, DiskReaderOptions};
+    use crate::storage::disk_writer::tests::{new_test_file, read_contents};
+
+    fn outdated_record(content: &[u8], deleted: bool) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(content.len() as u64).to_be_bytes());
+        buf.extend_from_slice(&crc32fast::hash(content).to_be_bytes());
+        buf.extend_from_slice(content);
+        buf.push(deleted as u8);
+        buf
+    }
+
+    fn outdated_records() -> Vec<u8> {
+        let mut records = Vec::new();
+        for i in 0..10 {
+            records.extend(outdated_record(format!("Record number {}!", i).as_bytes(), i % 3 == 0));
+        }
+        records
+    }
+
+    fn expected_contents() -> Vec<Vec<u8>> {
+        (0..10).filter(|i| i % 3 != 0).map(|i| format!("Record number {}!", i).into_bytes()).collect()
+    }
+
+    #[test]
+    fn version_1_file_should_be_rejected_then_upgraded() {
+        let file_name = new_test_file("version_1_file_should_be_rejected_then_upgraded");
+        let records = outdated_records();
+        let mut buf = Vec::new();
+        for field in [1, 10, V1_HEADER_SIZE + records.len() as u64, 1024] {
+            buf.extend_from_slice(&field.to_be_bytes());
+        }
+        buf.extend(records);
+        buf.resize(1024, 0);
+        std::fs::write(&file_name, &buf).unwrap();
+
+        assert!(matches!(DiskReader::new(&file_name, DiskReaderOptions::unlocked()), Err(StorageError::OutdatedVersion(1))));
+        assert!(matches!(DiskWriter::new(&file_name, 1024, DurabilityMode::EveryWrite), Err(StorageError::OutdatedVersion(1))));
+        assert_eq!(buf, std::fs::read(&file_name).unwrap());
+
+        let report = upgrade_file(&file_name).unwrap().unwrap();
+        assert_eq!((1, 6, 4), (report.from_version, report.records_kept, report.records_removed));
+        assert!(!Path::new(&format!("{}.upgrade", file_name)).exists());
+        assert_eq!(expected_contents(), read_contents(&file_name, DiskReaderOptions::unlocked()));
+        assert!(upgrade_file(&file_name).unwrap().is_none());
+
+        let mut writer = DiskWriter::new(&file_name, 1024, DurabilityMode::EveryWrite).unwrap();
+        assert_eq!(RECORDS_FILE_VERSION, writer.meta.get().version);
+        writer.add_record(b"after upgrade").unwrap();
+        let last = DiskReader::new(&file_name, DiskReaderOptions::unlocked()).unwrap().last().unwrap().unwrap();
+        assert_eq!((7, b"after upgrade".to_vec()), (last.lsn, last.content));
+    }
+
+    #[test]
+    fn future_version_should_be_rejected() {
+        let file_name = new_test_file("future_version_should_be_rejected");
+        let writer = DiskWriter::new(&file_name, 1024, DurabilityMode::EveryWrite).unwrap();
+        let future = RecordsFileMeta { version: RECORDS_FILE_VERSION + 1, ..writer.meta.get() };
+        drop(writer);
+        let file = OpenOptions::new().write(true).open(&file_name).unwrap();
+        RecordsFileMeta { sequence: future.sequence + 1, ..future }.write_slot(&file).unwrap();
+
+        let expected = RECORDS_FILE_VERSION + 1;
+        assert!(matches!(DiskReader::new(&file_name, DiskReaderOptions::unlocked()), Err(StorageError::UnsupportedVersion(v)) if v == expected));
+        assert!(matches!(DiskWriter::new(&file_name, 1024, DurabilityMode::EveryWrite), Err(StorageError::UnsupportedVersion(v)) if v == expected));
+        assert!(matches!(upgrade_file(&file_name), Err(StorageError::UnsupportedVersion(v)) if v == expected));
+    }
+
+}